@@ -184,9 +184,15 @@ defmodule Nopea.Git do
 
   @impl true
   def handle_info({port, {:data, data}}, %{port: port, caller: caller} = state) do
-    response = parse_response(data)
-    if caller, do: GenServer.reply(caller, response)
-    {:noreply, %{state | caller: nil}}
+    case parse_response(data) do
+      # Intermediate progress frame; the final response is still to come
+      :progress ->
+        {:noreply, state}
+
+      response ->
+        if caller, do: GenServer.reply(caller, response)
+        {:noreply, %{state | caller: nil}}
+    end
   end
 
   @impl true
@@ -266,6 +272,15 @@ defmodule Nopea.Git do
   end
 
   defp parse_git_response(%{"ok" => value}) when is_binary(value), do: {:ok, value}
+
+  # read: an LFS pointer the sidecar didn't resolve is not the manifest
+  defp parse_git_response(%{"ok" => %{"content" => _, "lfs_pointer" => true}}),
+    do: {:error, "file is an unresolved Git LFS pointer"}
+
+  # read: base64 content plus a binary flag
+  defp parse_git_response(%{"ok" => %{"content" => content, "is_binary" => _}})
+       when is_binary(content),
+       do: {:ok, content}
   defp parse_git_response(%{"ok" => files}) when is_list(files), do: {:ok, files}
 
   defp parse_git_response(%{
@@ -282,6 +297,12 @@ defmodule Nopea.Git do
     {:ok, %{sha: sha, author: author, email: email, message: msg, timestamp: ts}}
   end
 
+  defp parse_git_response(%{"progress" => _}), do: :progress
+
+  # Structured error; the message keeps the {:error, String.t()} contract
+  defp parse_git_response(%{"err" => %{"message" => message}}) when is_binary(message),
+    do: {:error, message}
+
   defp parse_git_response(%{"err" => reason}), do: {:error, reason}
 
   defp parse_git_response(other) do
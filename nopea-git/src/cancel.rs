@@ -0,0 +1,154 @@
+//! Cancelling in-flight requests by id.
+//!
+//! Every request with an id is registered while it runs (ids are scoped to
+//! the connection that sent them), and its worker thread carries the
+//! request's token. A `cancel` op flips the token: a
+//! request still queued answers `cancelled` as soon as it starts, and a
+//! network operation stops waiting at once (see `git::with_timeout`).
+//! The abandoned work then winds down as well: a wait for the repository
+//! lock gives up, libgit2 is aborted from its next remote callback, and a
+//! `git` child process is killed. A connect blocked inside libgit2 has no
+//! callback to abort it, so that thread only ends when the socket gives
+//! up. Local reads are quick and run to completion.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// How often a blocking wait (the repository lock, a `git` child) checks
+/// whether its request was cancelled
+pub const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Shared flag a request polls to learn it was cancelled
+#[derive(Debug, Clone, Default)]
+pub struct Token(Arc<AtomicBool>);
+
+impl Token {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn requests() -> &'static Mutex<HashMap<(u64, u64), Token>> {
+    static REQUESTS: OnceLock<Mutex<HashMap<(u64, u64), Token>>> = OnceLock::new();
+    REQUESTS.get_or_init(Default::default)
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Token>> = const { RefCell::new(None) };
+}
+
+/// A request's entry in the registry, removed on drop
+#[derive(Debug)]
+pub struct Registration {
+    key: (u64, u64),
+    token: Token,
+}
+
+impl Registration {
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut requests = requests().lock().unwrap_or_else(|e| e.into_inner());
+        // A later request may have reused the id; leave its entry alone
+        if requests
+            .get(&self.key)
+            .is_some_and(|token| Arc::ptr_eq(&token.0, &self.token.0))
+        {
+            requests.remove(&self.key);
+        }
+    }
+}
+
+/// Make request `id` from connection `conn` cancellable until the
+/// registration is dropped
+pub fn register(conn: u64, id: u64) -> Registration {
+    let token = Token::default();
+    requests()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert((conn, id), token.clone());
+    Registration {
+        key: (conn, id),
+        token,
+    }
+}
+
+/// Cancel request `id` from connection `conn`; false when no such request
+/// is queued or running
+pub fn cancel(conn: u64, id: u64) -> bool {
+    let requests = requests().lock().unwrap_or_else(|e| e.into_inner());
+    match requests.get(&(conn, id)) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Restores the previous token when dropped
+pub struct Entered(Option<Token>);
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+/// Run the rest of this thread's work (until the guard drops) on `token`
+pub fn enter(token: Token) -> Entered {
+    Entered(CURRENT.with(|current| current.borrow_mut().replace(token)))
+}
+
+/// Token of the request this thread is working on
+pub fn current() -> Option<Token> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Whether the request this thread is working on was cancelled
+pub fn is_cancelled() -> bool {
+    CURRENT.with(|current| current.borrow().as_ref().is_some_and(Token::is_cancelled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_reaches_the_entered_token() {
+        let registration = register(0, 9_001);
+        assert!(!is_cancelled());
+
+        let entered = enter(registration.token().clone());
+        assert!(!is_cancelled());
+        assert!(!cancel(1, 9_001));
+        assert!(cancel(0, 9_001));
+        assert!(is_cancelled());
+
+        drop(entered);
+        assert!(!is_cancelled());
+        drop(registration);
+        assert!(!cancel(0, 9_001));
+    }
+
+    #[test]
+    fn test_reused_id_keeps_the_newer_registration() {
+        let first = register(0, 9_002);
+        let second = register(0, 9_002);
+        drop(first);
+
+        assert!(cancel(0, 9_002));
+        assert!(second.token().is_cancelled());
+    }
+}
@@ -0,0 +1,345 @@
+//! Fallback to the `git` command line for features libgit2 lacks.
+//!
+//! libgit2 cannot do partial clones (`--filter=blob:none` and friends), so
+//! when a sync asks for a filter we shell out to `git` instead. This path is
+//! only available when a `git` binary is on `PATH`; `available()` is the
+//! capability flag callers should check before relying on it. Git LFS
+//! objects are downloaded the same way, through `git lfs smudge`
+//! (`lfs_available()`), and `gc` runs `git gc`.
+//!
+//! Credentials are handed to git through the environment rather than argv so
+//! they never show up in the process list or in error strings. SSH keys are
+//! passed via `GIT_SSH_COMMAND`; passphrase-protected keys need an agent.
+//! The proxy is resolved the same way as for libgit2 and pinned in the
+//! child's environment. A cancelled request kills its child.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::OnceLock;
+use std::thread::{self, JoinHandle};
+
+use base64::Engine;
+use zeroize::Zeroizing;
+
+use crate::cancel;
+use crate::git::{is_anonymous_url, user_agent, GitError, RefType, SyncOptions, Transport};
+
+/// Proxy variables git reads from the environment
+const PROXY_VARS: [&str; 8] = [
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+    "NO_PROXY",
+    "no_proxy",
+];
+
+/// Whether a usable `git` binary is on `PATH` (checked once per process)
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        Command::new("git")
+            .arg("--version")
+            .output()
+            .map(|out| out.status.success())
+            .unwrap_or(false)
+    })
+}
+
+/// Whether the `git lfs` extension is installed (checked once per process)
+pub fn lfs_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        available()
+            && Command::new("git")
+                .args(["lfs", "version"])
+                .output()
+                .map(|out| out.status.success())
+                .unwrap_or(false)
+    })
+}
+
+/// Content of the object an LFS `pointer` for `file` refers to, downloaded
+/// from the LFS endpoint of `url` unless the repository already has it
+pub fn lfs_smudge(
+    url: &str,
+    path: &Path,
+    file: &str,
+    pointer: &[u8],
+    transport: &Transport,
+) -> Result<Vec<u8>, GitError> {
+    if !lfs_available() {
+        return Err(GitError::Unsupported(
+            "git-lfs is not installed (required to resolve LFS pointers)".to_string(),
+        ));
+    }
+
+    let mut cmd = git(url, transport)?;
+    cmd.arg("-C")
+        .arg(path)
+        .args(["lfs", "smudge", "--"])
+        .arg(file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    // Pointers are tiny, so this can't fill the pipe before smudge reads it
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(pointer)?;
+    }
+
+    let output = wait(child)?;
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+    Err(failure(&output.stderr))
+}
+
+/// Partial clone of `name` into `path` using the given object filter
+pub fn clone_filtered(
+    url: &str,
+    name: &str,
+    path: &Path,
+    depth: u32,
+    opts: &SyncOptions,
+    filter: &str,
+    transport: &Transport,
+) -> Result<(), GitError> {
+    let mut cmd = git(url, transport)?;
+    cmd.arg("clone")
+        .arg(format!("--filter={}", filter))
+        .arg("--origin")
+        .arg(opts.remote_name())
+        .arg("--branch")
+        .arg(name);
+    if opts.fetch_tags {
+        // Applied before the initial fetch, and kept for later ones
+        cmd.arg("--config")
+            .arg(format!("remote.{}.tagOpt=--tags", opts.remote_name()));
+    }
+    if depth > 0 {
+        cmd.arg("--depth").arg(depth.to_string());
+    }
+    if opts.single_branch {
+        cmd.arg("--single-branch");
+    }
+    cmd.arg("--").arg(url).arg(path);
+
+    run(cmd)
+}
+
+/// `git gc` in `path`: repack into a single pack and prune unreachable
+/// loose objects past git's default expiry
+pub fn gc(path: &Path) -> Result<(), GitError> {
+    if !available() {
+        return Err(GitError::Unsupported(
+            "git binary not found on PATH (required for gc)".to_string(),
+        ));
+    }
+
+    let mut cmd = Command::new("git");
+    cmd.env("GIT_TERMINAL_PROMPT", "0")
+        .arg("-C")
+        .arg(path)
+        .args(["gc", "--quiet"]);
+    run(cmd)
+}
+
+/// Filtered fetch of `name` from the sync's remote followed by a hard reset onto it
+pub fn fetch_and_reset_filtered(
+    url: &str,
+    path: &Path,
+    name: &str,
+    opts: &SyncOptions,
+    filter: &str,
+    transport: &Transport,
+) -> Result<(), GitError> {
+    let remote = opts.remote_name();
+    let refspec = opts.ref_type.refspec(remote, name);
+    let fetched = opts.ref_type.fetched_ref(remote, name);
+
+    let mut fetch = git(url, transport)?;
+    fetch
+        .arg("-C")
+        .arg(path)
+        .arg("fetch")
+        .arg(format!("--filter={}", filter));
+    if opts.fetch_tags {
+        fetch.arg("--tags");
+    }
+    fetch.arg(remote).arg(refspec);
+    run(fetch)?;
+
+    // `fetch --prune` only prunes within the refspec given on the command
+    // line, so prune against the remote's configured refspecs separately
+    if opts.prune {
+        let mut prune = git(url, transport)?;
+        prune
+            .arg("-C")
+            .arg(path)
+            .arg("remote")
+            .arg("prune")
+            .arg(remote);
+        run(prune)?;
+    }
+
+    // Missing blobs are fetched on demand from the promisor remote during
+    // checkout, which is why this can't go through libgit2's reset.
+    let mut reset = git(url, transport)?;
+    reset.arg("-C").arg(path);
+    match opts.ref_type {
+        RefType::Branch => reset.arg("reset").arg("--hard").arg(fetched),
+        RefType::Tag => reset
+            .arg("checkout")
+            .arg("--force")
+            .arg("--detach")
+            .arg(fetched),
+    };
+    run(reset)
+}
+
+/// Build a `git` command carrying the caller's credentials and proxy for `url`
+/// in its environment
+fn git(url: &str, transport: &Transport) -> Result<Command, GitError> {
+    if !available() {
+        return Err(GitError::Cli(
+            "git binary not found on PATH (required for partial clone filters and LFS)".to_string(),
+        ));
+    }
+
+    let mut cmd = Command::new("git");
+    // Never block on an interactive prompt
+    cmd.env("GIT_TERMINAL_PROMPT", "0");
+
+    // Pin the proxy we resolved so git's own env handling can't disagree
+    for var in PROXY_VARS {
+        cmd.env_remove(var);
+    }
+    if let Some(proxy) = transport.proxy_for(url) {
+        cmd.env("HTTPS_PROXY", &proxy).env("HTTP_PROXY", &proxy);
+    }
+
+    if let Some(ca_cert) = &transport.ca_cert_path {
+        if Path::new(ca_cert).is_dir() {
+            cmd.env("GIT_SSL_CAPATH", ca_cert);
+        } else {
+            cmd.env("GIT_SSL_CAINFO", ca_cert);
+        }
+    }
+    cmd.env("GIT_HTTP_USER_AGENT", user_agent());
+    if transport.insecure_skip_tls_verify {
+        tracing::warn!(
+            "INSECURE: skipping TLS certificate verification (insecure_skip_tls_verify is set); never use this in production"
+        );
+        cmd.env("GIT_SSL_NO_VERIFY", "1");
+    }
+
+    let creds = transport.creds();
+    // Anonymous remotes (file://, git://, local paths) have nothing to send
+    let userpass = creds.userpass().filter(|_| !is_anonymous_url(url));
+    if let Some((username, password)) = userpass {
+        let pair = Zeroizing::new(format!("{}:{}", username, password));
+        let token = Zeroizing::new(base64::engine::general_purpose::STANDARD.encode(&*pair));
+        cmd.env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+            .env(
+                "GIT_CONFIG_VALUE_0",
+                format!("Authorization: Basic {}", &*token),
+            );
+    }
+
+    // Same host key policy as the libgit2 path, except that a non-strict
+    // first connection records the new host like `ssh` does
+    let mut ssh = format!(
+        "ssh -o StrictHostKeyChecking={}",
+        if transport.strict_host_key_checking {
+            "yes"
+        } else {
+            "accept-new"
+        }
+    );
+    if let Some(known_hosts) = &transport.known_hosts_path {
+        ssh.push_str(&format!(
+            " -o UserKnownHostsFile={}",
+            shell_quote(known_hosts)
+        ));
+    }
+    if let Some(key) = &creds.ssh_private_key_path {
+        ssh.push_str(&format!(" -i {} -o IdentitiesOnly=yes", shell_quote(key)));
+    }
+    cmd.env("GIT_SSH_COMMAND", ssh);
+
+    Ok(cmd)
+}
+
+/// Single-quote `value` for the shell git runs `GIT_SSH_COMMAND` with
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Run a prepared command, turning a non-zero exit into `GitError::Cli`
+fn run(mut cmd: Command) -> Result<(), GitError> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = wait(cmd.spawn()?)?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(failure(&output.stderr))
+}
+
+/// Collect `child`'s output like `wait_with_output`, but kill it and fail
+/// with `Cancelled` if the current request is cancelled first
+fn wait(mut child: Child) -> Result<Output, GitError> {
+    if cancel::current().is_none() {
+        return Ok(child.wait_with_output()?);
+    }
+    // Drained on their own threads so a chatty child can't fill a pipe
+    // and stall while we poll
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancel::is_cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(GitError::Cancelled);
+        }
+        thread::sleep(cancel::WAIT_POLL_INTERVAL);
+    };
+
+    let collect = |pipe: Option<JoinHandle<Vec<u8>>>| {
+        pipe.and_then(|pipe| pipe.join().ok()).unwrap_or_default()
+    };
+    Ok(Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+/// Read `pipe` to the end on a new thread
+fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Error for a failed git command, from what it printed to stderr
+fn failure(stderr: &[u8]) -> GitError {
+    let stderr = String::from_utf8_lossy(stderr).trim().to_string();
+    if stderr.contains("SSL certificate problem")
+        || stderr.contains("server certificate verification failed")
+    {
+        return GitError::CertificateVerification(stderr);
+    }
+    GitError::Cli(stderr)
+}
@@ -0,0 +1,51 @@
+//! Shell-style wildcard matching for ref, host and file patterns.
+
+/// Whether `text` matches `pattern`, where `*` matches any run of
+/// characters (including none) and `?` matches exactly one
+pub fn matches(pattern: &str, text: &str) -> bool {
+    matches_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+/// Match a relative file path: a pattern containing `/` is matched against
+/// the whole path, one without against just the file name (so `_*.yaml`
+/// catches `templates/_helpers.yaml`)
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        matches(pattern, path)
+    } else {
+        matches(pattern, path.rsplit('/').next().unwrap_or(path))
+    }
+}
+
+fn matches_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| matches_bytes(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && matches_bytes(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && matches_bytes(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_wildcards() {
+        assert!(matches("release-*", "release-1.2"));
+        assert!(matches("release-*", "release-"));
+        assert!(matches("v?.0", "v2.0"));
+        assert!(!matches("v?.0", "v10.0"));
+        assert!(!matches("release-*", "hotfix-release-1"));
+        assert!(matches("main", "main"));
+    }
+
+    #[test]
+    fn test_matches_path_by_name_or_whole_path() {
+        assert!(matches_path("_helpers.yaml", "_helpers.yaml"));
+        assert!(matches_path("_*.yaml", "templates/_helpers.yaml"));
+        assert!(matches_path("templates/*", "templates/_helpers.yaml"));
+        assert!(!matches_path("templates/*", "charts/templates.yaml"));
+        assert!(!matches_path("values.yaml", "values.yaml.tpl"));
+    }
+}
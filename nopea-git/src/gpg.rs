@@ -0,0 +1,302 @@
+//! OpenPGP signature checks through the `gpg` binary.
+//!
+//! libgit2 can extract a commit's signature but not verify it, so we hand
+//! the signature and the signed payload to `gpg --verify` and read its
+//! machine-readable status output. Public keys come from the keyring of the
+//! process (`GNUPGHOME`); a signature from a key that isn't in the keyring
+//! is reported as not valid, with the issuer fingerprint when gpg knows it.
+//! Just naming the signer, as commit info does, is done by parsing the
+//! signature packet, so it needs neither gpg nor the key.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base64::Engine;
+
+use crate::git::GitError;
+
+/// Outcome of checking one signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// Whether gpg verified the signature against a key in the keyring
+    pub valid: bool,
+    /// Fingerprint (or long key id, if that's all gpg reports) of the signer
+    pub fingerprint: Option<String>,
+}
+
+/// Verify a detached ASCII-armored signature over `payload`
+pub fn verify(signature: &[u8], payload: &[u8]) -> Result<Verdict, GitError> {
+    // gpg reads the signature from a file and the signed data from stdin
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let sig_path = std::env::temp_dir().join(format!(
+        "nopea-sig-{}-{}.asc",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&sig_path, signature)?;
+
+    let result = run_gpg(&sig_path, payload);
+    let _ = std::fs::remove_file(&sig_path);
+    let status = result?;
+
+    Ok(parse_status(&status))
+}
+
+fn run_gpg(sig_path: &std::path::Path, payload: &[u8]) -> Result<String, GitError> {
+    let mut child = Command::new("gpg")
+        .args(["--batch", "--no-tty", "--status-fd", "1", "--verify"])
+        .arg(sig_path)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => GitError::Unsupported(
+                "gpg binary not found on PATH (required for signature verification)".to_string(),
+            ),
+            _ => GitError::Io(e),
+        })?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // gpg stops reading early when the signature itself is unusable
+        match stdin.write_all(payload) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            other => other?,
+        }
+    }
+    // A bad or unverifiable signature exits non-zero; the status lines say why
+    let output = child.wait_with_output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Interpret `gpg --status-fd` output
+pub(crate) fn parse_status(status: &str) -> Verdict {
+    let mut valid = false;
+    let mut bad = false;
+    let mut fingerprint = None;
+    let mut key_id = None;
+
+    for line in status.lines() {
+        let fields: Vec<&str> = match line.strip_prefix("[GNUPG:] ") {
+            Some(rest) => rest.split_whitespace().collect(),
+            None => continue,
+        };
+        match fields.as_slice() {
+            ["VALIDSIG", fpr, ..] => {
+                valid = true;
+                fingerprint = Some(fpr.to_string());
+            }
+            ["BADSIG", id, ..] => {
+                bad = true;
+                key_id.get_or_insert_with(|| id.to_string());
+            }
+            // ERRSIG <keyid> <algo> <hash> <class> <time> <rc> [<fpr>]
+            ["ERRSIG", id, rest @ ..] => {
+                key_id.get_or_insert_with(|| id.to_string());
+                if let Some(fpr) = rest.get(5).filter(|f| **f != "-") {
+                    fingerprint.get_or_insert_with(|| fpr.to_string());
+                }
+            }
+            ["GOODSIG", id, ..] | ["EXPKEYSIG", id, ..] | ["REVKEYSIG", id, ..] => {
+                key_id.get_or_insert_with(|| id.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    Verdict {
+        valid: valid && !bad,
+        fingerprint: fingerprint.or(key_id),
+    }
+}
+
+/// Fingerprint (or long key id) of the key that made an ASCII-armored
+/// OpenPGP signature, read from its issuer subpackets without calling gpg.
+///
+/// Says nothing about validity. Anything unparseable (including SSH
+/// signatures) is `None`.
+pub fn issuer(signature: &[u8]) -> Option<String> {
+    let packet = dearmor(std::str::from_utf8(signature).ok()?)?;
+    let body = signature_packet_body(&packet)?;
+
+    // v4: version, type, key algo, hash algo, then the two subpacket areas
+    if body.first() != Some(&4) {
+        return None;
+    }
+    let hashed_len = u16::from_be_bytes([*body.get(4)?, *body.get(5)?]) as usize;
+    let hashed = body.get(6..6 + hashed_len)?;
+    let rest = &body[6 + hashed_len..];
+    let unhashed_len = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+    let unhashed = rest.get(2..2 + unhashed_len)?;
+
+    let mut fingerprint = None;
+    let mut key_id = None;
+    for (kind, data) in subpackets(hashed).chain(subpackets(unhashed)) {
+        match kind {
+            // Issuer fingerprint: key version, then the fingerprint
+            33 if data.len() > 1 => {
+                fingerprint.get_or_insert_with(|| hex(&data[1..]));
+            }
+            // Issuer key id
+            16 if data.len() == 8 => {
+                key_id.get_or_insert_with(|| hex(data));
+            }
+            _ => {}
+        }
+    }
+    fingerprint.or(key_id)
+}
+
+/// Binary content of an ASCII-armored block (checksum not verified)
+fn dearmor(armored: &str) -> Option<Vec<u8>> {
+    let mut lines = armored.lines().map(str::trim);
+    lines.find(|line| line.starts_with("-----BEGIN PGP "))?;
+    // Armor headers ("Comment: ...") end at the first blank line
+    let encoded: String = lines
+        .skip_while(|line| !line.is_empty())
+        .skip(1)
+        .take_while(|line| !line.starts_with('=') && !line.starts_with("-----"))
+        .collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()
+}
+
+/// Body of the leading packet, if it's a signature packet (tag 2)
+fn signature_packet_body(data: &[u8]) -> Option<&[u8]> {
+    let header = *data.first()?;
+    if header & 0x80 == 0 {
+        return None;
+    }
+    let (tag, len, start) = if header & 0x40 != 0 {
+        let (len, size) = new_format_length(data.get(1..)?)?;
+        (header & 0x3f, len, 1 + size)
+    } else {
+        let size = match header & 0x03 {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            _ => return None,
+        };
+        let len = data
+            .get(1..1 + size)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        ((header >> 2) & 0x0f, len, 1 + size)
+    };
+    if tag != 2 {
+        return None;
+    }
+    data.get(start..start + len)
+}
+
+/// A new-format length (also used for subpackets): value and octets used
+fn new_format_length(data: &[u8]) -> Option<(usize, usize)> {
+    match *data.first()? {
+        first @ 0..=191 => Some((first as usize, 1)),
+        first @ 192..=254 => {
+            let second = *data.get(1)? as usize;
+            Some((((first as usize - 192) << 8) + second + 192, 2))
+        }
+        255 => {
+            let bytes = data.get(1..5)?;
+            Some((u32::from_be_bytes(bytes.try_into().ok()?) as usize, 5))
+        }
+    }
+}
+
+/// Subpackets of a signature as (type, data), critical bit cleared
+fn subpackets(mut area: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (len, size) = new_format_length(area)?;
+        let packet = area.get(size..size + len)?;
+        area = &area[size + len..];
+        let (kind, data) = packet.split_first()?;
+        Some((kind & 0x7f, data))
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Detached signature by an ed25519 key with fingerprint
+    /// 605CC3CA913EE737187BFC77CC272A43F269884B
+    const SIGNATURE: &str = "\
+-----BEGIN PGP SIGNATURE-----
+
+iIYEABYIAC4WIQRgXMPKkT7nNxh7/HfMJypD8mmISwUCas9E7hAcYm90QGV4YW1w
+bGUuY29tAAoJEMwnKkPyaYhLi20A/1nXqaDLJBxTW6tna0kGf4c80OpY/gO66STu
+QuslipkqAP0Y1uQb66hRQ4tf6Q3KGaqBLKUneTzt42zwaNPMQ/9WAg==
+=goFI
+-----END PGP SIGNATURE-----
+";
+
+    #[test]
+    fn test_issuer_reads_fingerprint_subpacket() {
+        assert_eq!(
+            issuer(SIGNATURE.as_bytes()).as_deref(),
+            Some("605CC3CA913EE737187BFC77CC272A43F269884B")
+        );
+    }
+
+    #[test]
+    fn test_issuer_of_garbage_is_none() {
+        assert_eq!(issuer(b"not a signature"), None);
+        assert_eq!(issuer(b"-----BEGIN SSH SIGNATURE-----\nU1NIU0lH\n"), None);
+        let truncated = SIGNATURE.replace(
+            "QuslipkqAP0Y1uQb66hRQ4tf6Q3KGaqBLKUneTzt42zwaNPMQ/9WAg==\n",
+            "",
+        );
+        assert_eq!(issuer(truncated.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_parse_status_valid_signature() {
+        let status = "\
+[GNUPG:] NEWSIG
+[GNUPG:] GOODSIG 0123456789ABCDEF Deploy Bot <bot@example.com>
+[GNUPG:] VALIDSIG 89ABCDEF0123456789ABCDEF0123456789ABCDEF 2024-01-01 1704067200 0 4 0 22 8 00 89ABCDEF0123456789ABCDEF0123456789ABCDEF
+[GNUPG:] TRUST_UNDEFINED 0 pgp";
+
+        let verdict = parse_status(status);
+
+        assert!(verdict.valid);
+        assert_eq!(
+            verdict.fingerprint.as_deref(),
+            Some("89ABCDEF0123456789ABCDEF0123456789ABCDEF")
+        );
+    }
+
+    #[test]
+    fn test_parse_status_unknown_key_reports_issuer() {
+        let status = "\
+[GNUPG:] NEWSIG
+[GNUPG:] ERRSIG 0123456789ABCDEF 22 8 00 1704067200 9 89ABCDEF0123456789ABCDEF0123456789ABCDEF
+[GNUPG:] NO_PUBKEY 0123456789ABCDEF";
+
+        let verdict = parse_status(status);
+
+        assert!(!verdict.valid);
+        assert_eq!(
+            verdict.fingerprint.as_deref(),
+            Some("89ABCDEF0123456789ABCDEF0123456789ABCDEF")
+        );
+    }
+
+    #[test]
+    fn test_parse_status_bad_signature_is_invalid() {
+        let status = "[GNUPG:] BADSIG 0123456789ABCDEF Deploy Bot <bot@example.com>";
+
+        let verdict = parse_status(status);
+
+        assert!(!verdict.valid);
+        assert_eq!(verdict.fingerprint.as_deref(), Some("0123456789ABCDEF"));
+    }
+}
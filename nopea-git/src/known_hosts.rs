@@ -0,0 +1,212 @@
+//! SSH host key checks against an OpenSSH `known_hosts` file.
+//!
+//! libgit2 hands the server's raw host key to the certificate callback; we
+//! look the host up the way `ssh` does (plain, wildcard and hashed `|1|`
+//! patterns, `[host]:port` entries) and compare it with the recorded keys of
+//! the same type. `@revoked` entries reject a key outright; `@cert-authority`
+//! entries are not supported and ignored.
+
+use std::path::PathBuf;
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::glob;
+
+/// Outcome of looking up one host key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The host is listed with this exact key
+    Trusted,
+    /// The host has no key of this type on record
+    Unknown,
+    /// The host is listed with a different key of the same type
+    Mismatch,
+    /// The key is marked `@revoked`
+    Revoked,
+}
+
+/// `~/.ssh/known_hosts`, if `HOME` is set
+pub fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+/// Check `key` (the raw SSH wire-format key blob) for `host` against the
+/// contents of a known_hosts file
+pub fn check(contents: &str, host: &str, key: &[u8]) -> Verdict {
+    let key_type = blob_type(key);
+    let mut verdict = Verdict::Unknown;
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (marker, patterns) = match fields.next() {
+            Some(marker) if marker.starts_with('@') => (Some(marker), fields.next()),
+            patterns => (None, patterns),
+        };
+        let (Some(patterns), Some(_), Some(encoded)) = (patterns, fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Ok(recorded) = base64::engine::general_purpose::STANDARD.decode(encoded) else {
+            continue;
+        };
+
+        match marker {
+            Some("@revoked") if recorded == key => return Verdict::Revoked,
+            Some(_) => continue,
+            None => {}
+        }
+        if !host_matches(patterns, host) || blob_type(&recorded) != key_type {
+            continue;
+        }
+        if recorded == key {
+            verdict = Verdict::Trusted;
+        } else if verdict == Verdict::Unknown {
+            verdict = Verdict::Mismatch;
+        }
+    }
+
+    verdict
+}
+
+/// Whether a comma-separated pattern list names `host`; a matching `!`
+/// pattern excludes the host even if another pattern matches
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if pattern_matches(negated, host) {
+                return false;
+            }
+        } else if pattern_matches(pattern, host) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn pattern_matches(pattern: &str, host: &str) -> bool {
+    if let Some(hashed) = pattern.strip_prefix("|1|") {
+        return hashed_matches(hashed, host);
+    }
+    // libgit2 doesn't pass the port, so `[host]:port` entries match any port
+    let pattern = match pattern.strip_prefix('[').and_then(|p| p.split_once("]:")) {
+        Some((name, _port)) => name,
+        None => pattern,
+    };
+    glob::matches(&pattern.to_lowercase(), &host.to_lowercase())
+}
+
+/// `|1|salt|hash`: base64 HMAC-SHA1 of the host name keyed with the salt
+fn hashed_matches(hashed: &str, host: &str) -> bool {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (engine.decode(salt), engine.decode(hash)) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(&salt) else {
+        return false;
+    };
+    mac.update(host.as_bytes());
+    mac.verify_slice(&hash).is_ok()
+}
+
+/// Key type name (`ssh-ed25519`, ...) at the start of a key blob
+fn blob_type(blob: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    blob.get(4..4 + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake key blob of the given type
+    fn blob(key_type: &str, body: &[u8]) -> Vec<u8> {
+        let mut blob = (key_type.len() as u32).to_be_bytes().to_vec();
+        blob.extend_from_slice(key_type.as_bytes());
+        blob.extend_from_slice(body);
+        blob
+    }
+
+    fn line(patterns: &str, key: &[u8]) -> String {
+        let key_type = String::from_utf8_lossy(blob_type(key).unwrap()).into_owned();
+        format!(
+            "{} {} {}",
+            patterns,
+            key_type,
+            base64::engine::general_purpose::STANDARD.encode(key)
+        )
+    }
+
+    #[test]
+    fn test_check_matches_plain_and_wildcard_hosts() {
+        let key = blob("ssh-ed25519", b"github");
+        let other = blob("ssh-ed25519", b"attacker");
+        let contents = format!(
+            "# comment\n{}\n{}\n",
+            line("github.com,140.82.112.3", &key),
+            line("*.example.com,!evil.example.com", &other)
+        );
+
+        assert_eq!(check(&contents, "github.com", &key), Verdict::Trusted);
+        assert_eq!(check(&contents, "GitHub.com", &key), Verdict::Trusted);
+        assert_eq!(check(&contents, "github.com", &other), Verdict::Mismatch);
+        assert_eq!(
+            check(&contents, "git.example.com", &other),
+            Verdict::Trusted
+        );
+        assert_eq!(
+            check(&contents, "evil.example.com", &other),
+            Verdict::Unknown
+        );
+        assert_eq!(check(&contents, "gitlab.com", &key), Verdict::Unknown);
+        // A key of another type is not a mismatch, just not on record
+        let rsa = blob("ssh-rsa", b"github");
+        assert_eq!(check(&contents, "github.com", &rsa), Verdict::Unknown);
+    }
+
+    #[test]
+    fn test_check_matches_hashed_and_bracketed_hosts() {
+        let key = blob("ecdsa-sha2-nistp256", b"server");
+        let salt = b"0123456789abcdefghij";
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).unwrap();
+        mac.update(b"git.internal");
+        let engine = base64::engine::general_purpose::STANDARD;
+        let hashed = format!(
+            "|1|{}|{}",
+            engine.encode(salt),
+            engine.encode(mac.finalize().into_bytes())
+        );
+        let contents = format!(
+            "{}\n{}\n",
+            line(&hashed, &key),
+            line("[ssh.internal]:2222", &key)
+        );
+
+        assert_eq!(check(&contents, "git.internal", &key), Verdict::Trusted);
+        assert_eq!(check(&contents, "ssh.internal", &key), Verdict::Trusted);
+        assert_eq!(check(&contents, "other.internal", &key), Verdict::Unknown);
+    }
+
+    #[test]
+    fn test_check_rejects_revoked_keys() {
+        let key = blob("ssh-ed25519", b"leaked");
+        let contents = format!(
+            "{}\n@revoked {}\n",
+            line("github.com", &key),
+            line("*", &key)
+        );
+
+        assert_eq!(check(&contents, "github.com", &key), Verdict::Revoked);
+    }
+}
@@ -1,56 +1,608 @@
 //! nopea-git: Git operations port for NOPEA
 //!
-//! Communicates via length-prefixed msgpack over stdin/stdout.
-//! Protocol: 4-byte big-endian length + msgpack payload
+//! Communicates via length-prefixed msgpack over stdin/stdout, or over
+//! each connection to a Unix socket (`--listen PATH`) and/or a TCP port
+//! (`--listen-tcp HOST:PORT`). Protocol: 4-byte big-endian length + msgpack
+//! payload.
+//!
+//! The TCP listener has no authentication or encryption: anyone who can
+//! connect can sync, read and push with the sidecar's credentials. Only
+//! expose it inside a trusted network or behind an mTLS proxy.
 
+mod cancel;
+mod cli;
 mod git;
+mod glob;
+mod gpg;
+mod known_hosts;
+mod logging;
+mod metrics;
+mod pool;
 mod protocol;
+mod proxy;
 
 use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use protocol::{Request, Response};
+use signal_hook::consts::{SIGINT, SIGTERM};
 
-fn main() {
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-    let mut stdin = stdin.lock();
-    let mut stdout = stdout.lock();
+use protocol::{
+    Envelope, EnvelopeHeader, ErrorCode, ErrorInfo, Reply, Request, Response, COMPRESS_THRESHOLD,
+    FRAME_RAW, FRAME_ZSTD, MAX_FRAME_SIZE,
+};
 
-    loop {
-        match read_request(&mut stdin) {
-            Ok(request) => {
-                let response = handle_request(request);
-                if let Err(e) = write_response(&mut stdout, &response) {
-                    eprintln!("Failed to write response: {}", e);
-                    break;
+fn main() -> ExitCode {
+    logging::init();
+    metrics::init();
+    // Read now, so a bad value is reported at startup
+    protocol::default_depth();
+    git::init_user_agent();
+
+    // `nopea-git <op> ...` runs one request, for use from a shell
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| !arg.starts_with('-')) {
+        return run_once(&args);
+    }
+
+    let (sender, events) = mpsc::sync_channel(0);
+    spawn_signal_watcher(sender.clone());
+    let socket_path = option_value("--listen", "NOPEA_LISTEN").map(PathBuf::from);
+    let tcp_addr = option_value("--listen-tcp", "NOPEA_LISTEN_TCP");
+    if socket_path.is_none() && tcp_addr.is_none() {
+        spawn_stdin_reader(sender.clone());
+    }
+
+    // Held until exit, which removes the socket file again
+    let _socket = socket_path.map(|path| {
+        listen(&path, sender.clone()).unwrap_or_else(|e| {
+            tracing::error!(error = %e, path = %path.display(), "failed to listen");
+            std::process::exit(1);
+        })
+    });
+    if let Some(addr) = tcp_addr {
+        if let Err(e) = listen_tcp(&addr, sender) {
+            tracing::error!(error = %e, addr, "failed to listen");
+            std::process::exit(1);
+        }
+    }
+
+    serve(&events);
+    ExitCode::SUCCESS
+}
+
+/// Run the request described by `args`, print the response as JSON and
+/// exit non-zero if it failed.
+///
+/// `args` is the op, then an optional JSON object of arguments, then
+/// `--name value` flags (dashes in names become underscores). A value that
+/// parses as JSON is taken as such (`--depth 1`, `--files '["a.yaml"]'`),
+/// anything else as a string (quote it as JSON, `--branch '"2024"'`, to
+/// keep a number a string); a flag without a value is `true`.
+fn run_once(args: &[String]) -> ExitCode {
+    let request = match request_from_args(args) {
+        Ok(request) => request,
+        Err(message) => {
+            eprintln!("nopea-git: {}", message);
+            return ExitCode::from(2);
+        }
+    };
+    let response = handle_request(None, false, request, &Responder::new(0, io::sink()));
+    match serde_json::to_string_pretty(&response) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("nopea-git: failed to encode response: {}", e),
+    }
+    match response {
+        Response::Err(_) => ExitCode::FAILURE,
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+/// Build a request from one-shot command line arguments (see `run_once`)
+fn request_from_args(args: &[String]) -> Result<Request, String> {
+    let (op, mut rest) = match args.split_first() {
+        Some((op, rest)) => (op, rest),
+        None => return Err("missing op".to_string()),
+    };
+    let mut fields = match rest.first().filter(|arg| !arg.starts_with("--")) {
+        Some(json) => {
+            rest = &rest[1..];
+            match serde_json::from_str(json) {
+                Ok(serde_json::Value::Object(fields)) => fields,
+                _ => return Err(format!("arguments must be a JSON object, got {}", json)),
+            }
+        }
+        None => serde_json::Map::new(),
+    };
+
+    while let Some((flag, tail)) = rest.split_first() {
+        let Some(name) = flag.strip_prefix("--") else {
+            return Err(format!("expected a --flag, got {}", flag));
+        };
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => match tail.first().filter(|next| !next.starts_with("--")) {
+                Some(value) => {
+                    rest = &rest[1..];
+                    (name, Some(value.clone()))
                 }
+                None => (name, None),
+            },
+        };
+        rest = &rest[1..];
+        let value = match value {
+            Some(value) => serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value)),
+            None => serde_json::Value::Bool(true),
+        };
+        fields.insert(name.replace('-', "_"), value);
+    }
+
+    fields.insert("op".to_string(), serde_json::Value::String(op.clone()));
+    serde_json::from_value(serde_json::Value::Object(fields)).map_err(|e| e.to_string())
+}
+
+/// Answer requests from every connection until shutdown, a termination
+/// signal, or the end of stdin.
+///
+/// All connections share one worker pool. Requests run concurrently, so
+/// responses may arrive out of order; clients correlate them by `id`.
+/// Frames for one request keep their order. This loop never waits on the
+/// workers: `cancel` is answered here, and a request arriving while the
+/// queue is full is answered `busy` at once, so stalled fetches can't stop
+/// frames from being read.
+fn serve(events: &Receiver<Event>) {
+    let pool = pool::Pool::new(pool::worker_count());
+
+    loop {
+        let (frame, out) = match events.recv() {
+            Ok(Event::Frame { frame, out }) => (*frame, out),
+            Ok(Event::Signal(signal)) => {
+                // Stop reading, but let in-flight syncs finish writing so
+                // the pod doesn't restart onto a half-updated repository
+                tracing::info!(signal, "termination signal received, draining requests");
+                break;
             }
-            Err(_) => {
-                // EOF or read error - exit cleanly
+            Err(_) => break,
+        };
+        let Envelope {
+            id,
+            timing,
+            compress,
+            request,
+        } = match frame {
+            Ok(envelope) => envelope,
+            // The frame was consumed whole, so the stream is still in sync
+            Err(ReadError::Rejected { header, error }) => {
+                tracing::warn!(id = header.id, error = %error.message, "rejected request frame");
+                let response = Response::Err(error);
+                out.send(&Reply {
+                    compress: header.compress,
+                    ..Reply::new(header.id, &response)
+                });
+                continue;
+            }
+            // EOF - exit cleanly
+            Err(ReadError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(ReadError::Io(e)) => {
+                tracing::error!(error = %e, "read error (shutting down)");
                 break;
             }
+        };
+
+        if matches!(request, Request::Shutdown) {
+            // Let in-flight requests finish before saying goodbye
+            pool.join();
+            out.send(&Reply {
+                compress,
+                ..Reply::new(id, &handle_request(id, compress, request, &out))
+            });
+            return;
+        }
+
+        // Answered right away: queued behind the request it cancels, a
+        // cancel would be too late
+        if matches!(request, Request::Cancel { .. }) {
+            out.send(&Reply {
+                compress,
+                ..Reply::new(id, &handle_request(id, compress, request, &out))
+            });
+            continue;
+        }
+
+        // Registered from queueing until answered, so `cancel` can find it
+        let registration = id.map(|id| cancel::register(out.conn, id));
+        let (op, busy) = (request.op(), out.clone());
+        let queued = pool.try_execute(move || {
+            let _current = registration
+                .as_ref()
+                .map(|registration| cancel::enter(registration.token().clone()));
+            let span = tracing::info_span!(
+                "request",
+                op = request.op(),
+                id,
+                path = request.target().as_deref()
+            );
+            let _entered = span.enter();
+            let op = request.op();
+            let started = Instant::now();
+
+            // A panicking request must still get an answer
+            let response = if cancel::is_cancelled() {
+                Response::Err(git::GitError::Cancelled.into())
+            } else {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle_request(id, compress, request, &out)
+                }))
+                .unwrap_or_else(|_| {
+                    Response::Err(ErrorInfo::new(
+                        ErrorCode::Internal,
+                        "request handler panicked",
+                    ))
+                })
+            };
+
+            let elapsed = started.elapsed();
+            let duration_ms = elapsed.as_millis() as u64;
+            let error = match &response {
+                Response::Err(e) => Some(e.code),
+                _ => None,
+            };
+            metrics::record_request(op, error, elapsed);
+            match &response {
+                Response::Err(e) => tracing::warn!(
+                    duration_ms,
+                    code = ?e.code,
+                    error = %e.message,
+                    "request failed"
+                ),
+                _ => tracing::info!(duration_ms, "request completed"),
+            }
+            out.send(&Reply {
+                duration_ms: timing.then_some(duration_ms),
+                compress,
+                ..Reply::new(id, &response)
+            });
+        });
+        // The rejected job, and with it the registration, is already dropped
+        if queued.is_err() {
+            tracing::warn!(op, id, "worker queue full, rejecting request");
+            metrics::record_request(op, Some(ErrorCode::Busy), Duration::ZERO);
+            let response = Response::Err(ErrorInfo::new(
+                ErrorCode::Busy,
+                "all workers are busy and the request queue is full; retry later",
+            ));
+            busy.send(&Reply {
+                compress,
+                ..Reply::new(id, &response)
+            });
+        }
+    }
+
+    // Answer whatever is still running before exiting
+    pool.join();
+}
+
+/// Something for the main loop to act on
+enum Event {
+    /// A request frame (or the error reading it), and where to answer it
+    Frame {
+        frame: Box<Result<Envelope, ReadError>>,
+        out: Responder,
+    },
+    /// SIGTERM or SIGINT
+    Signal(i32),
+}
+
+/// Where one connection's response frames go.
+///
+/// Shared by every worker answering the connection; holding the lock for
+/// the whole frame keeps concurrent frames from interleaving on the wire.
+#[derive(Clone)]
+struct Responder {
+    /// Connection number, scoping request ids for `cancel` (0 is stdio)
+    conn: u64,
+    out: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl Responder {
+    fn new(conn: u64, out: impl Write + Send + 'static) -> Self {
+        Self {
+            conn,
+            out: Arc::new(Mutex::new(out)),
+        }
+    }
+
+    /// Write one response frame
+    fn send(&self, reply: &Reply) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = write_response(&mut *out, reply) {
+            tracing::error!(error = %e, conn = self.conn, "failed to write response");
         }
     }
 }
 
-fn read_request<R: Read>(reader: &mut R) -> Result<Request, io::Error> {
+/// Read frames from `reader` on the calling thread and hand them to the
+/// main loop, answering through `out`.
+///
+/// The channel holds nothing, so the next frame is only read once the main
+/// loop asks for it. That loop never blocks on the workers, so a flood past
+/// the worker queue is answered `busy` rather than left unread.
+/// With `forward_close` the error that ends the stream is passed on too,
+/// which stops the main loop.
+fn forward_frames<R: Read>(
+    mut reader: R,
+    out: Responder,
+    sender: &SyncSender<Event>,
+    forward_close: bool,
+) {
+    loop {
+        let frame = read_request(&mut reader);
+        if let Err(ReadError::Io(e)) = &frame {
+            if !forward_close {
+                tracing::debug!(conn = out.conn, error = %e, "connection closed");
+                return;
+            }
+        }
+        let fatal = matches!(frame, Err(ReadError::Io(_)));
+        let event = Event::Frame {
+            frame: Box::new(frame),
+            out: out.clone(),
+        };
+        if sender.send(event).is_err() || fatal {
+            return;
+        }
+    }
+}
+
+/// Serve the Port on stdin/stdout; its end stops the sidecar
+fn spawn_stdin_reader(sender: SyncSender<Event>) {
+    thread::spawn(move || {
+        forward_frames(
+            io::stdin().lock(),
+            Responder::new(0, io::stdout()),
+            &sender,
+            true,
+        )
+    });
+}
+
+/// Wait for termination signals on their own thread, so a signal is seen
+/// even while the main loop is waiting for the next frame
+fn spawn_signal_watcher(sender: SyncSender<Event>) {
+    match signal_hook::iterator::Signals::new([SIGTERM, SIGINT]) {
+        Ok(mut signals) => {
+            thread::spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    let _ = sender.send(Event::Signal(signal));
+                }
+            });
+        }
+        Err(e) => tracing::error!(error = %e, "failed to install signal handlers"),
+    }
+}
+
+/// Value of `flag VALUE` (or `flag=VALUE`) on the command line, else of
+/// the `env` variable
+fn option_value(flag: &str, env: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(flag).and_then(|v| v.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    std::env::var(env).ok().filter(|value| !value.is_empty())
+}
+
+/// A stream a connection can be served over
+trait Connection: Read + Write + Send + Sized + 'static {
+    /// A second handle, so reads and writes can happen on different threads
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+/// Serve every connection from `incoming` on its own reader thread
+fn accept<S, I>(incoming: I, sender: SyncSender<Event>)
+where
+    S: Connection,
+    I: Iterator<Item = io::Result<S>> + Send + 'static,
+{
+    // Connection numbers are shared by all listeners; 0 is stdio
+    static NEXT_CONN: AtomicU64 = AtomicU64::new(1);
+
+    thread::spawn(move || {
+        for stream in incoming {
+            let (reader, writer) = match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to accept connection");
+                    continue;
+                }
+            };
+            let conn = NEXT_CONN.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(conn, "connection accepted");
+            let sender = sender.clone();
+            thread::spawn(move || {
+                forward_frames(reader, Responder::new(conn, writer), &sender, false)
+            });
+        }
+    });
+}
+
+/// A listening socket's file, removed when dropped
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Accept connections on a Unix socket at `path`, each speaking the same
+/// framing as stdio, until the sidecar exits.
+///
+/// A stale socket left by a process that didn't exit cleanly is replaced;
+/// one with a live listener (or any other file) makes this fail.
+fn listen(path: &Path, sender: SyncSender<Event>) -> io::Result<SocketFile> {
+    let stale = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+    if stale {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    tracing::info!(path = %path.display(), "listening on unix socket");
+
+    accept(
+        std::iter::from_fn(move || Some(listener.accept().map(|(stream, _)| stream))),
+        sender,
+    );
+    Ok(SocketFile(path.to_path_buf()))
+}
+
+/// Accept connections on a TCP address, each speaking the same framing as
+/// stdio, until the sidecar exits; answers with the bound address.
+///
+/// There is no authentication: see the module docs before exposing it.
+fn listen_tcp(addr: &str, sender: SyncSender<Event>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    tracing::warn!(
+        addr = %local,
+        "listening on tcp without authentication; expose only to trusted networks"
+    );
+
+    accept(
+        std::iter::from_fn(move || Some(listener.accept().map(|(stream, _)| stream))),
+        sender,
+    );
+    Ok(local)
+}
+
+/// Progress sink that writes each update to `out` as a `progress` frame
+fn progress_frames(id: Option<u64>, compress: bool, out: &Responder) -> git::ProgressSink {
+    let out = out.clone();
+    git::ProgressSink(Arc::new(move |progress| {
+        out.send(&Reply {
+            compress,
+            ..Reply::new(id, &Response::Progress(progress))
+        });
+    }))
+}
+
+/// Why `read_request` returned no request
+#[derive(Debug)]
+enum ReadError {
+    /// The stream is closed or can no longer be trusted to be in sync
+    Io(io::Error),
+    /// A whole frame was consumed but can't be served; answer and read on
+    Rejected {
+        header: EnvelopeHeader,
+        error: ErrorInfo,
+    },
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+fn read_request<R: Read>(reader: &mut R) -> Result<Envelope, ReadError> {
     // Read 4-byte length prefix (big-endian)
     let mut len_buf = [0u8; 4];
     reader.read_exact(&mut len_buf)?;
     let len = u32::from_be_bytes(len_buf) as usize;
 
+    if len == 0 {
+        return Err(ReadError::Rejected {
+            header: EnvelopeHeader::default(),
+            error: ErrorInfo::new(
+                ErrorCode::InvalidRequest,
+                "empty request frame (length prefix 0)",
+            ),
+        });
+    }
+
+    // Reject before allocating: a corrupt prefix must not OOM us. The
+    // payload is skipped rather than buffered so the next frame still lines
+    // up; if it never arrives, that is EOF like any other.
+    if len > MAX_FRAME_SIZE {
+        let skipped = io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+        if skipped < len as u64 {
+            return Err(ReadError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "stream ended inside a request frame of {} bytes (over the {} byte limit)",
+                    len, MAX_FRAME_SIZE
+                ),
+            )));
+        }
+        return Err(ReadError::Rejected {
+            header: EnvelopeHeader::default(),
+            error: ErrorInfo::new(
+                ErrorCode::TooLarge,
+                format!(
+                    "request frame of {} bytes exceeds the {} byte limit",
+                    len, MAX_FRAME_SIZE
+                ),
+            ),
+        });
+    }
+
     // Read payload
     let mut payload = vec![0u8; len];
     reader.read_exact(&mut payload)?;
 
     // Deserialize msgpack
-    rmp_serde::from_slice(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    rmp_serde::from_slice(&payload).map_err(|error| ReadError::Rejected {
+        header: EnvelopeHeader::peek(&payload),
+        error: ErrorInfo::new(
+            ErrorCode::InvalidRequest,
+            format!("malformed request: {}", error),
+        ),
+    })
 }
 
-fn write_response<W: Write>(writer: &mut W, response: &Response) -> Result<(), io::Error> {
-    // Serialize to msgpack with named fields (maps instead of arrays)
-    let payload = rmp_serde::to_vec_named(response)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+fn write_response<W: Write + ?Sized>(writer: &mut W, response: &Reply) -> Result<(), io::Error> {
+    let mut payload = encode_reply(response)?;
+
+    // The client enforces the same cap, so answer with an error instead
+    if payload.len() > MAX_FRAME_SIZE {
+        let too_large = Response::Err(ErrorInfo::new(
+            ErrorCode::TooLarge,
+            format!(
+                "response of {} bytes exceeds the {} byte frame limit",
+                payload.len(),
+                MAX_FRAME_SIZE
+            ),
+        ));
+        payload = encode_reply(&Reply {
+            compress: response.compress,
+            ..Reply::new(response.id, &too_large)
+        })?;
+    }
 
     // Write 4-byte length prefix (big-endian)
     let len = payload.len() as u32;
@@ -63,41 +615,697 @@ fn write_response<W: Write>(writer: &mut W, response: &Response) -> Result<(), i
     Ok(())
 }
 
-fn handle_request(request: Request) -> Response {
+/// Frame payload for a reply: msgpack, behind a header byte (and compressed
+/// when large) if the request asked for `compress`
+fn encode_reply(reply: &Reply) -> Result<Vec<u8>, io::Error> {
+    // Serialize to msgpack with named fields (maps instead of arrays)
+    let payload = rmp_serde::to_vec_named(reply)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if !reply.compress {
+        return Ok(payload);
+    }
+
+    if payload.len() < COMPRESS_THRESHOLD {
+        let mut framed = Vec::with_capacity(payload.len() + 1);
+        framed.push(FRAME_RAW);
+        framed.extend_from_slice(&payload);
+        return Ok(framed);
+    }
+    let mut framed = vec![FRAME_ZSTD];
+    framed.extend_from_slice(&zstd::bulk::compress(&payload, 0)?);
+    Ok(framed)
+}
+
+fn handle_request(id: Option<u64>, compress: bool, request: Request, out: &Responder) -> Response {
+    if let Some(Err(e)) = request.repo_path().map(git::confine) {
+        return Response::Err(e.into());
+    }
+
     match request {
+        Request::Hello {
+            version,
+            user_agent,
+        } => {
+            if let Some(Err(e)) = user_agent.as_deref().map(git::set_user_agent) {
+                return Response::Err(e.into());
+            }
+            match protocol::hello(version) {
+                Ok(info) => Response::OkHello(info),
+                Err(e) => Response::Err(ErrorInfo::new(ErrorCode::Unsupported, e)),
+            }
+        }
+
         Request::Sync {
             url,
             branch,
             path,
             depth,
-        } => match git::sync(&url, &branch, &path, depth) {
-            Ok(commit) => Response::Ok(commit),
-            Err(e) => Response::Err(e.to_string()),
+            mut transport,
+            options,
+            report_progress,
+            verbose,
+        } => {
+            if report_progress {
+                transport.progress = Some(progress_frames(id, compress, out));
+            }
+            if options.dry_run {
+                return match git::sync_preview(&branch, &path, depth, &transport, &options) {
+                    Ok(preview) => Response::OkSyncPreview(preview),
+                    Err(e) => Response::Err(e.into()),
+                };
+            }
+            // Older callers expect the bare SHA unless they ask for more
+            if verbose || options.repair || options.report_changes {
+                return match git::sync_with_outcome(
+                    &url, &branch, &path, depth, &transport, &options,
+                ) {
+                    Ok(outcome) => Response::OkSync(outcome),
+                    Err(e) => Response::Err(e.into()),
+                };
+            }
+            match git::sync(&url, &branch, &path, depth, &transport, &options) {
+                Ok(commit) => Response::Ok(commit),
+                Err(e) => Response::Err(e.into()),
+            }
+        }
+
+        Request::FetchCommit {
+            url,
+            sha,
+            path,
+            depth,
+            transport,
+        } => match git::sync_commit(&url, &sha, &path, depth, &transport) {
+            Ok(sha) => Response::Ok(sha),
+            Err(e) => Response::Err(e.into()),
         },
 
-        Request::Files { path, subpath } => match git::list_files(&path, subpath.as_deref()) {
+        Request::Files {
+            path,
+            subpath,
+            with_hash: true,
+            options,
+        } => match git::list_file_hashes(&path, subpath.as_deref(), &options) {
+            Ok(hashes) => Response::OkHashes(hashes),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Files {
+            path,
+            subpath,
+            with_hash: false,
+            options,
+        } => match git::list_files(&path, subpath.as_deref(), &options) {
             Ok(files) => Response::OkFiles(files),
-            Err(e) => Response::Err(e.to_string()),
+            Err(e) => Response::Err(e.into()),
         },
 
-        Request::Read { path, file } => match git::read_file(&path, &file) {
+        Request::DetectSource { path, subpath } => {
+            match git::detect_source(&path, subpath.as_deref()) {
+                Ok(kind) => Response::OkSourceKind(kind),
+                Err(e) => Response::Err(e.into()),
+            }
+        }
+
+        Request::FilesDetailed {
+            path,
+            subpath,
+            recursive,
+            with_hash,
+        } => match git::list_files_detailed(&path, subpath.as_deref(), recursive, with_hash) {
+            Ok(entries) => Response::OkEntries(entries),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::FilesAt {
+            path,
+            subpath,
+            sha,
+            options,
+        } => match git::list_files_at(&path, subpath.as_deref(), &sha, &options) {
+            Ok(files) => Response::OkFiles(files),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Read {
+            path,
+            file,
+            offset: None,
+            length: None,
+            options,
+        } => match git::read_file(&path, &file, &options) {
+            Ok(content) => Response::OkContent(content),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Read {
+            path,
+            file,
+            offset,
+            length,
+            options,
+        } => match git::read_file_range(&path, &file, offset.unwrap_or(0), length, &options) {
+            Ok(chunk) => Response::OkChunk(chunk),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::ReadMany {
+            path,
+            files,
+            options,
+        } => {
+            let limit = protocol::max_batch_size();
+            if files.len() > limit {
+                return Response::Err(ErrorInfo::new(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "read of {} files exceeds the batch limit of {}",
+                        files.len(),
+                        limit
+                    ),
+                ));
+            }
+            let results = files
+                .into_iter()
+                .map(|file| {
+                    let result = match git::read_file(&path, &file, &options) {
+                        Ok(content) => Response::OkContent(content),
+                        Err(e) => Response::Err(e.into()),
+                    };
+                    (file, result)
+                })
+                .collect();
+            Response::OkFileMap(results)
+        }
+
+        Request::Stat { path, file } => match git::stat(&path, &file) {
+            Ok(stat) => Response::OkStat(stat),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::ReadAt {
+            path,
+            file,
+            sha,
+            options,
+        } => match git::read_file_at(&path, &file, &sha, &options) {
             Ok(content) => Response::Ok(content),
-            Err(e) => Response::Err(e.to_string()),
+            Err(e) => Response::Err(e.into()),
         },
 
         Request::Head { path } => match git::head(&path) {
+            Ok(info) => Response::OkHead(info),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Log { path, limit } => match git::log(&path, limit) {
+            Ok(commits) => Response::OkCommits(commits),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Diff {
+            path,
+            from,
+            to,
+            all_files,
+        } => match git::diff(&path, &from, &to, all_files) {
+            Ok(changes) => Response::OkChanges(changes),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Status { path } => match git::status(&path) {
+            Ok(status) => Response::OkStatus(status),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Checkout {
+            path,
+            rev,
+            reset_type,
+            verbose,
+            fetch_if_missing,
+            remote,
+            transport,
+        } => match reset_type
+            .as_deref()
+            .map_or(Ok(git::ResetMode::Hard), str::parse)
+            .and_then(|mode| {
+                let fetch_missing = fetch_if_missing.then_some(&transport);
+                git::checkout(&path, &rev, mode, remote.as_deref(), fetch_missing)
+            }) {
+            Ok(info) if verbose => Response::OkCommitInfo(info),
+            Ok(info) => Response::Ok(info.sha),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Resolve { path, rev } => match git::resolve(&path, &rev) {
             Ok(info) => Response::OkCommitInfo(info),
-            Err(e) => Response::Err(e.to_string()),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Verify {
+            path,
+            sha,
+            allowed_keys,
+        } => match git::verify(&path, &sha, &allowed_keys) {
+            Ok(status) => Response::OkSignature(status),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Branches { path, remote } => match git::branches(&path, remote.as_deref()) {
+            Ok(names) => Response::OkFiles(names),
+            Err(e) => Response::Err(e.into()),
         },
 
-        Request::Checkout { path, sha } => match git::checkout(&path, &sha) {
+        Request::Tags { path } => match git::tags(&path) {
+            Ok(tags) => Response::OkTags(tags),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::LsRemote {
+            url,
+            branch,
+            no_cache,
+            transport,
+        } => {
+            let ttl = match no_cache {
+                true => Duration::ZERO,
+                false => git::ls_remote_ttl(),
+            };
+            match git::ls_remote_cached(&url, &branch, &transport, ttl) {
+                Ok(sha) => Response::Ok(sha),
+                Err(e) => Response::Err(e.into()),
+            }
+        }
+
+        Request::LsRefs {
+            url,
+            pattern,
+            include_tags,
+            transport,
+        } => match git::ls_refs(&url, pattern.as_deref(), include_tags, &transport) {
+            Ok(refs) => Response::OkRefs(refs),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::DefaultBranch { url, transport } => match git::default_branch(&url, &transport) {
+            Ok(branch) => Response::OkDefaultBranch(branch),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::AheadBehind {
+            path,
+            branch,
+            remote,
+            transport,
+        } => match git::ahead_behind(&path, &branch, remote.as_deref(), &transport) {
+            Ok(counts) => Response::OkAheadBehind(counts),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Unshallow {
+            path,
+            remote,
+            transport,
+        } => match git::unshallow(&path, remote.as_deref(), &transport) {
             Ok(sha) => Response::Ok(sha),
-            Err(e) => Response::Err(e.to_string()),
+            Err(e) => Response::Err(e.into()),
         },
 
-        Request::LsRemote { url, branch } => match git::ls_remote(&url, &branch) {
+        Request::Gc { path } => match git::gc(&path) {
+            Ok(reclaimed) => Response::OkBytes(reclaimed),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::RepoSize { path } => match git::repo_size(&path) {
+            Ok(size) => Response::OkRepoSize(size),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Remove { path } => match git::remove(&path) {
+            Ok(reclaimed) => Response::OkBytes(reclaimed),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Exists { path } => Response::OkBool(git::exists(&path)),
+
+        Request::Commit {
+            path,
+            files,
+            message,
+            author_name,
+            author_email,
+        } => match git::commit(&path, &files, &message, &author_name, &author_email) {
+            Ok(info) => Response::OkCommitInfo(info),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::CreateTag {
+            path,
+            name,
+            target_sha,
+            message,
+            annotated,
+            force,
+        } => match git::create_tag(&path, &name, &target_sha, &message, annotated, force) {
+            Ok(tag) => Response::OkTag(tag),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Push {
+            path,
+            branch,
+            remote,
+            transport,
+        } => match git::push(&path, &branch, remote.as_deref(), &transport) {
             Ok(sha) => Response::Ok(sha),
-            Err(e) => Response::Err(e.to_string()),
+            Err(e) => Response::Err(e.into()),
+        },
+
+        Request::Ping => Response::Ok("pong".to_string()),
+
+        Request::Capabilities => Response::OkCapabilities(protocol::capabilities()),
+
+        Request::Version => Response::OkVersion(protocol::version()),
+
+        Request::Metrics => match metrics::render() {
+            Some(text) => Response::Ok(text),
+            None => Response::Err(ErrorInfo::new(
+                ErrorCode::Unsupported,
+                "metrics are disabled (set NOPEA_METRICS=1)",
+            )),
         },
+
+        // The main loop drains the worker pool before dispatching this
+        Request::Shutdown => Response::Ok("bye".to_string()),
+
+        Request::Cancel { request_id } => Response::OkBool(cancel::cancel(out.conn, request_id)),
+
+        Request::Batch { requests } => {
+            let limit = protocol::max_batch_size();
+            if requests.len() > limit {
+                return Response::Err(ErrorInfo::new(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "batch of {} requests exceeds the limit of {}",
+                        requests.len(),
+                        limit
+                    ),
+                ));
+            }
+            let responses = requests
+                .into_iter()
+                .map(|request| match request {
+                    Request::Batch { .. } => Response::Err(ErrorInfo::new(
+                        ErrorCode::InvalidRequest,
+                        "batch requests cannot be nested",
+                    )),
+                    Request::Shutdown => Response::Err(ErrorInfo::new(
+                        ErrorCode::InvalidRequest,
+                        "shutdown cannot be batched",
+                    )),
+                    request => handle_request(id, compress, request, out),
+                })
+                .collect();
+            Response::OkBatch(responses)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length-prefix a payload the way clients do
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut framed = (payload.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(payload);
+        framed
+    }
+
+    /// Frame for an op without arguments
+    fn op_frame(id: u64, op: &str) -> Vec<u8> {
+        #[derive(serde::Serialize)]
+        struct Frame<'a> {
+            id: u64,
+            op: &'a str,
+        }
+        frame(&rmp_serde::to_vec_named(&Frame { id, op }).unwrap())
+    }
+
+    fn ping_frame(id: u64) -> Vec<u8> {
+        op_frame(id, "ping")
+    }
+
+    /// A reply carrying a string, as to `ping` or `shutdown`
+    #[derive(serde::Deserialize)]
+    struct Answer {
+        id: u64,
+        ok: String,
+    }
+
+    fn answer(stream: &mut impl Read) -> Answer {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        rmp_serde::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_read_request_rejects_oversized_length_prefix() {
+        // Length prefix claims ~4 GiB with no payload behind it
+        let mut input = io::Cursor::new(u32::MAX.to_be_bytes().to_vec());
+
+        let Err(ReadError::Io(err)) = read_request(&mut input) else {
+            panic!("expected an io error");
+        };
+
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("over the"));
+    }
+
+    #[test]
+    fn test_read_request_skips_oversized_frame() {
+        let len = MAX_FRAME_SIZE + 1;
+        // Streamed, so the test doesn't hold the oversized payload either
+        let mut input = io::Cursor::new((len as u32).to_be_bytes().to_vec())
+            .chain(io::repeat(0).take(len as u64))
+            .chain(io::Cursor::new(ping_frame(3)));
+
+        let Err(ReadError::Rejected { error, .. }) = read_request(&mut input) else {
+            panic!("expected a rejected frame");
+        };
+        assert_eq!(error.code, ErrorCode::TooLarge);
+        assert!(error.message.contains("exceeds"));
+
+        let envelope = read_request(&mut input).unwrap();
+        assert_eq!(envelope.id, Some(3));
+    }
+
+    #[test]
+    fn test_read_request_rejects_empty_frame() {
+        let mut input = io::Cursor::new([frame(&[]), ping_frame(4)].concat());
+
+        let Err(ReadError::Rejected { header, error }) = read_request(&mut input) else {
+            panic!("expected a rejected frame");
+        };
+        assert_eq!(header.id, None);
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert!(error.message.contains("length prefix 0"));
+
+        assert_eq!(read_request(&mut input).unwrap().id, Some(4));
+    }
+
+    #[test]
+    fn test_read_request_recovers_after_malformed_frame() {
+        #[derive(serde::Serialize)]
+        struct Frame<'a> {
+            id: u64,
+            op: &'a str,
+        }
+        let mut input = frame(&rmp_serde::to_vec_named(&Frame { id: 7, op: "nope" }).unwrap());
+        input.extend(frame(&[0xc1, 0xff, 0x00]));
+        input.extend(ping_frame(8));
+        let mut input = io::Cursor::new(input);
+
+        // An unknown op still has a readable id to answer to
+        let Err(ReadError::Rejected { header, .. }) = read_request(&mut input) else {
+            panic!("expected a rejected frame");
+        };
+        assert_eq!(header.id, Some(7));
+        // Garbage that isn't msgpack at all
+        let Err(ReadError::Rejected { header, .. }) = read_request(&mut input) else {
+            panic!("expected a rejected frame");
+        };
+        assert_eq!(header.id, None);
+
+        let envelope = read_request(&mut input).unwrap();
+        assert_eq!(envelope.id, Some(8));
+        assert!(matches!(envelope.request, Request::Ping));
+        assert!(matches!(
+            read_request(&mut input),
+            Err(ReadError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_write_response_replaces_oversized_payload_with_error() {
+        let response = Response::Ok("x".repeat(MAX_FRAME_SIZE + 1));
+        let mut out = Vec::new();
+
+        write_response(&mut out, &Reply::new(Some(1), &response)).unwrap();
+
+        let len = u32::from_be_bytes(out[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, out.len() - 4);
+        assert!(len < 1024);
+        let frame = String::from_utf8_lossy(&out[4..]);
+        assert!(frame.contains("too_large"));
+    }
+
+    #[test]
+    fn test_unix_socket_serves_each_connection() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("nopea.sock");
+        // A leftover socket nobody listens on is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let (sender, events) = mpsc::sync_channel(0);
+        let socket = listen(&path, sender).unwrap();
+        let server = thread::spawn(move || serve(&events));
+
+        let mut first = UnixStream::connect(&path).unwrap();
+        let mut second = UnixStream::connect(&path).unwrap();
+        second.write_all(&ping_frame(1)).unwrap();
+        first.write_all(&ping_frame(2)).unwrap();
+        let (a, b) = (answer(&mut first), answer(&mut second));
+        assert_eq!((a.id, a.ok.as_str()), (2, "pong"));
+        assert_eq!((b.id, b.ok.as_str()), (1, "pong"));
+
+        // One client closing doesn't stop the others
+        drop(first);
+        second.write_all(&op_frame(3, "shutdown")).unwrap();
+        assert_eq!(answer(&mut second).ok, "bye");
+        server.join().unwrap();
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_tcp_listener_serves_the_same_framing() {
+        let (sender, events) = mpsc::sync_channel(0);
+        let addr = listen_tcp("127.0.0.1:0", sender).unwrap();
+        let server = thread::spawn(move || serve(&events));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&ping_frame(5)).unwrap();
+        let reply = answer(&mut stream);
+        assert_eq!((reply.id, reply.ok.as_str()), (5, "pong"));
+
+        stream.write_all(&op_frame(6, "shutdown")).unwrap();
+        assert_eq!(answer(&mut stream).ok, "bye");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_from_args_reads_json_and_flags() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+
+        let request = request_from_args(&args(&[
+            "sync",
+            r#"{"path": "/data/app"}"#,
+            "--url",
+            "https://example.com/app.git",
+            "--branch=main",
+            "--depth",
+            "1",
+            "--report-progress",
+        ]))
+        .unwrap();
+        let Request::Sync {
+            url,
+            branch,
+            path,
+            depth,
+            report_progress,
+            ..
+        } = request
+        else {
+            panic!("expected a sync request, got {:?}", request);
+        };
+        assert_eq!(url, "https://example.com/app.git");
+        assert_eq!(branch, "main");
+        assert_eq!(path, "/data/app");
+        assert_eq!(depth, 1);
+        assert!(report_progress);
+
+        let request = request_from_args(&args(&[
+            "readmany",
+            "--path",
+            "/r",
+            "--files",
+            r#"["a.yaml"]"#,
+        ]))
+        .unwrap();
+        assert!(matches!(request, Request::ReadMany { files, .. } if files == ["a.yaml"]));
+
+        assert!(request_from_args(&args(&["ping"])).is_ok());
+        assert!(request_from_args(&args(&["head", "stray"])).is_err());
+        assert!(request_from_args(&args(&["nope"])).is_err());
+    }
+
+    #[test]
+    fn test_read_many_reports_each_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("a.yaml"), "kind: ConfigMap").unwrap();
+
+        let response = handle_request(
+            None,
+            false,
+            Request::ReadMany {
+                path: dir.path().to_str().unwrap().to_string(),
+                files: vec!["a.yaml".to_string(), "missing.yaml".to_string()],
+                options: Default::default(),
+            },
+            &Responder::new(0, io::sink()),
+        );
+
+        let Response::OkFileMap(results) = response else {
+            panic!("expected a file map, got {:?}", response);
+        };
+        assert!(matches!(results["a.yaml"], Response::OkContent(_)));
+        assert!(matches!(
+            &results["missing.yaml"],
+            Response::Err(e) if e.code == ErrorCode::FileNotFound
+        ));
+    }
+
+    #[test]
+    fn test_compressed_frames_carry_header_byte() {
+        let frame = |response: &Response| {
+            let mut out = Vec::new();
+            let reply = Reply {
+                compress: true,
+                ..Reply::new(Some(7), response)
+            };
+            write_response(&mut out, &reply).unwrap();
+            let len = u32::from_be_bytes(out[..4].try_into().unwrap()) as usize;
+            assert_eq!(len, out.len() - 4);
+            out.split_off(4)
+        };
+
+        let small = frame(&Response::Ok("pong".to_string()));
+        assert_eq!(small[0], FRAME_RAW);
+        assert!(String::from_utf8_lossy(&small[1..]).contains("pong"));
+
+        let big = Response::Ok("a".repeat(COMPRESS_THRESHOLD * 4));
+        let compressed = frame(&big);
+        assert_eq!(compressed[0], FRAME_ZSTD);
+        assert!(compressed.len() < COMPRESS_THRESHOLD);
+        let payload = zstd::decode_all(&compressed[1..]).unwrap();
+        assert_eq!(
+            payload,
+            rmp_serde::to_vec_named(&Reply::new(Some(7), &big)).unwrap()
+        );
     }
 }
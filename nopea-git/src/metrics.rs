@@ -0,0 +1,116 @@
+//! Prometheus-style metrics.
+//!
+//! Off unless `NOPEA_METRICS` is set (to anything but `0` or `false`); the
+//! `metrics` macros are no-ops until a recorder is installed, so the
+//! instrumentation costs nothing by default. The sidecar has no HTTP
+//! server, so the `metrics` op returns the Prometheus text exposition for
+//! the node to serve or forward.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use crate::protocol::ErrorCode;
+
+/// Latency buckets in seconds: ls-remote round trips up to large clones
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Whether `NOPEA_METRICS` asks for metrics
+fn requested() -> bool {
+    std::env::var("NOPEA_METRICS").is_ok_and(|v| {
+        let v = v.trim();
+        !v.is_empty() && v != "0" && !v.eq_ignore_ascii_case("false")
+    })
+}
+
+fn builder() -> PrometheusBuilder {
+    PrometheusBuilder::new()
+        .set_buckets(DURATION_BUCKETS)
+        .expect("bucket list is not empty")
+}
+
+/// Install the global recorder if metrics are enabled; call once at startup
+pub fn init() {
+    if !requested() {
+        return;
+    }
+    match builder().install_recorder() {
+        Ok(handle) => {
+            let _ = HANDLE.set(handle);
+        }
+        Err(e) => tracing::error!(error = %e, "failed to install metrics recorder"),
+    }
+}
+
+/// Whether the recorder is installed
+pub fn enabled() -> bool {
+    HANDLE.get().is_some()
+}
+
+/// Current metrics in the Prometheus text format, or `None` when disabled
+pub fn render() -> Option<String> {
+    let handle = HANDLE.get()?;
+    handle.run_upkeep();
+    Some(handle.render())
+}
+
+/// Count a finished request and record its latency, labelled by op and
+/// result (`ok` or the error code)
+pub fn record_request(op: &'static str, error: Option<ErrorCode>, elapsed: Duration) {
+    let result = error.map_or_else(
+        || "ok".to_string(),
+        |code| snake_case(&format!("{:?}", code)),
+    );
+    ::metrics::counter!("nopea_requests_total", "op" => op, "result" => result).increment(1);
+    ::metrics::histogram!("nopea_request_duration_seconds", "op" => op)
+        .record(elapsed.as_secs_f64());
+}
+
+/// Count bytes downloaded by fetches and clones
+pub fn record_fetched_bytes(bytes: u64) {
+    ::metrics::counter!("nopea_fetch_bytes_total").increment(bytes);
+}
+
+/// `AuthFailed` -> `auth_failed`, matching the error codes on the wire
+fn snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_requests_by_op_and_result() {
+        let recorder = builder().build_recorder();
+        let handle = recorder.handle();
+
+        ::metrics::with_local_recorder(&recorder, || {
+            record_request("sync", None, Duration::from_millis(40));
+            record_request("sync", Some(ErrorCode::AuthFailed), Duration::from_secs(2));
+            record_fetched_bytes(1024);
+        });
+        let text = handle.render();
+
+        assert!(text.contains(r#"nopea_requests_total{op="sync",result="ok"} 1"#));
+        assert!(text.contains(r#"nopea_requests_total{op="sync",result="auth_failed"} 1"#));
+        assert!(text.contains(r#"nopea_request_duration_seconds_bucket{op="sync",le="0.05"} 1"#));
+        assert!(text.contains("nopea_fetch_bytes_total 1024"));
+    }
+}
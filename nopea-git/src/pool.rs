@@ -0,0 +1,179 @@
+//! Fixed-size worker pool for handling requests concurrently.
+//!
+//! The queue in front of the workers is bounded too, so a client that
+//! floods us with requests is turned away (`try_execute` fails) instead of
+//! growing an unbounded backlog in memory. Queueing never blocks: the
+//! caller is the loop that also answers `cancel`, which must keep running
+//! while every worker is stuck on a stalled fetch.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Worker count used when `NOPEA_WORKERS` is unset
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// Jobs that may wait in the queue per worker
+const QUEUE_PER_WORKER: usize = 8;
+
+/// Number of workers configured for this process
+pub fn worker_count() -> usize {
+    std::env::var("NOPEA_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_WORKERS)
+}
+
+pub struct Pool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    /// Start `size` workers (at least one)
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Job>(size * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || run_worker(&receiver))
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queue a job, or drop it and return `Full` if the queue is full
+    pub fn try_execute<F>(&self, job: F) -> Result<(), Full>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        match sender.try_send(Box::new(job)) {
+            Err(TrySendError::Full(_)) => Err(Full),
+            // Workers only exit once the sender is dropped, so the queue
+            // can't be disconnected
+            Ok(()) | Err(TrySendError::Disconnected(_)) => Ok(()),
+        }
+    }
+
+    /// Wait for every queued and running job to finish
+    pub fn join(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Every worker is busy and the queue is full
+#[derive(Debug, PartialEq, Eq)]
+pub struct Full;
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_worker(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => job(),
+            // Sender dropped and queue drained
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_pool_runs_jobs_concurrently() {
+        let pool = Pool::new(2);
+        // Both jobs must be running at once for the barrier to release
+        let barrier = Arc::new(Barrier::new(2));
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..2 {
+            let barrier = Arc::clone(&barrier);
+            let done = Arc::clone(&done);
+            assert_eq!(
+                pool.try_execute(move || {
+                    barrier.wait();
+                    done.fetch_add(1, Ordering::SeqCst);
+                }),
+                Ok(())
+            );
+        }
+        pool.join();
+
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_join_waits_for_queued_jobs() {
+        let pool = Pool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..5 {
+            let done = Arc::clone(&done);
+            assert_eq!(
+                pool.try_execute(move || {
+                    thread::sleep(std::time::Duration::from_millis(10));
+                    done.fetch_add(1, Ordering::SeqCst);
+                }),
+                Ok(())
+            );
+        }
+        pool.join();
+
+        assert_eq!(done.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_full_queue_rejects_without_blocking() {
+        let pool = Pool::new(1);
+        let (release, stalled) = mpsc::channel::<()>();
+        let stalled = Mutex::new(stalled);
+        assert_eq!(
+            pool.try_execute(move || {
+                let _ = stalled.lock().unwrap().recv();
+            }),
+            Ok(())
+        );
+
+        // The stalled job plus a full queue, then turned away
+        let accepted = (0..=QUEUE_PER_WORKER + 1)
+            .take_while(|_| pool.try_execute(|| {}).is_ok())
+            .count();
+        // One fewer if the worker hadn't picked up the stalled job yet
+        assert!((QUEUE_PER_WORKER - 1..=QUEUE_PER_WORKER).contains(&accepted));
+
+        drop(release);
+        pool.join();
+    }
+}
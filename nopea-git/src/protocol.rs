@@ -1,44 +1,669 @@
 //! Protocol definitions for nopea-git
+//!
+//! Every request gets exactly one final `{"ok": ...}` or `{"err": ...}`
+//! frame. A `sync` with `report_progress: true` additionally streams
+//! `{"progress": {...}}` frames before the final one. Clients that send an
+//! `id` get it echoed on every frame for that request, so they can correlate
+//! (or skip) the intermediate frames; clients that never ask for progress
+//! never see them.
+//!
+//! Requests are handled concurrently, so responses to different requests may
+//! come back in any order. Clients with more than one request in flight must
+//! set `id`.
+//!
+//! A request with `compress: true` gets every response frame with one extra
+//! byte after the length prefix: `FRAME_RAW` followed by plain msgpack, or
+//! `FRAME_ZSTD` followed by zstd-compressed msgpack. Payloads under
+//! `COMPRESS_THRESHOLD` stay raw. Without `compress` the frame is unchanged.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
 
 use serde::{ser::SerializeMap, Deserialize, Serialize};
 
-use crate::git::CommitInfo;
+use crate::git::{
+    self, AheadBehind, CommitInfo, FileChange, FileChunk, FileContent, FileEntry, FileHash,
+    FileStat, FileWrite, GitError, HeadInfo, ListOptions, ReadOptions, RemoteRef, RepoSize,
+    SignatureStatus, SourceKind, SyncOptions, SyncOutcome, SyncPreview, TagInfo, TransferProgress,
+    Transport, WorkTreeStatus,
+};
+use crate::{cli, logging, metrics};
+
+/// Version of the wire protocol spoken by this sidecar.
+///
+/// Bump it when a change would make an older client misread responses.
+/// Adding ops or optional fields does not need a bump: clients discover
+/// those through the `hello` op list.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest frame payload accepted or sent, in bytes
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Frame header byte (with `compress`): plain msgpack follows
+pub const FRAME_RAW: u8 = 0;
+
+/// Frame header byte (with `compress`): zstd-compressed msgpack follows
+pub const FRAME_ZSTD: u8 = 1;
+
+/// Smallest payload worth compressing, in bytes
+pub const COMPRESS_THRESHOLD: usize = 4 * 1024;
+
+/// Oldest client protocol version this sidecar still serves
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Every op this sidecar understands, as sent in the `op` field
+pub const SUPPORTED_OPS: &[&str] = &[
+    "hello",
+    "sync",
+    "fetchcommit",
+    "files",
+    "detectsource",
+    "filesdetailed",
+    "filesat",
+    "read",
+    "readmany",
+    "stat",
+    "readat",
+    "head",
+    "log",
+    "diff",
+    "status",
+    "checkout",
+    "resolve",
+    "verify",
+    "branches",
+    "tags",
+    "aheadbehind",
+    "lsremote",
+    "lsrefs",
+    "defaultbranch",
+    "unshallow",
+    "gc",
+    "reposize",
+    "exists",
+    "remove",
+    "commit",
+    "createtag",
+    "push",
+    "ping",
+    "metrics",
+    "capabilities",
+    "version",
+    "batch",
+    "cancel",
+    "shutdown",
+];
+
+/// A request frame: the op plus the envelope fields every op accepts
+#[derive(Debug, Deserialize)]
+pub struct Envelope {
+    /// Caller-chosen id, echoed on every response frame for this request
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// Report how long the request took as `duration_ms` on the response
+    #[serde(default)]
+    pub timing: bool,
+    /// Send this request's response frames with a header byte, compressing
+    /// large payloads with zstd
+    #[serde(default)]
+    pub compress: bool,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// Just the addressing fields of a frame whose request didn't decode, so
+/// the error reply still reaches its caller in the framing it asked for
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EnvelopeHeader {
+    pub id: Option<u64>,
+    pub compress: bool,
+}
+
+impl EnvelopeHeader {
+    /// Best-effort read of `id` and `compress`; defaults if even those are
+    /// unreadable
+    pub fn peek(payload: &[u8]) -> Self {
+        rmp_serde::from_slice(payload).unwrap_or_default()
+    }
+}
 
 /// Request from Elixir to Rust
 #[derive(Debug, Deserialize)]
 #[serde(tag = "op", rename_all = "lowercase")]
 pub enum Request {
-    /// Clone or fetch a repository
+    /// Optional handshake: check compatibility and learn the supported ops.
+    /// `user_agent` replaces the HTTP user agent for the whole process (see
+    /// `git::set_user_agent`).
+    Hello {
+        version: u32,
+        #[serde(default)]
+        user_agent: Option<String>,
+    },
+
+    /// Clone or fetch a repository at a branch (or tag, via `ref_type`)
     Sync {
         url: String,
         branch: String,
         path: String,
         #[serde(default = "default_depth")]
         depth: u32,
+        #[serde(flatten)]
+        transport: Transport,
+        #[serde(flatten)]
+        options: SyncOptions,
+        /// Stream `progress` frames while fetching
+        #[serde(default)]
+        report_progress: bool,
+        /// Answer with `{old_sha, new_sha, changed, recloned}` instead of
+        /// the bare SHA
+        #[serde(default)]
+        verbose: bool,
     },
 
-    /// List files in a directory
+    /// Check out one commit by SHA, cloning if needed. Servers that refuse
+    /// fetching by SHA get a full fetch of every branch instead.
+    FetchCommit {
+        url: String,
+        sha: String,
+        path: String,
+        #[serde(default = "default_depth")]
+        depth: u32,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// List files in a directory; `with_hash` returns `{name, sha256}` pairs
+    /// instead of bare names
     Files {
         path: String,
         #[serde(default)]
         subpath: Option<String>,
+        #[serde(default)]
+        with_hash: bool,
+        #[serde(flatten)]
+        options: ListOptions,
+    },
+
+    /// Whether a directory is a Kustomize, Helm or plain-manifest source
+    DetectSource {
+        path: String,
+        #[serde(default)]
+        subpath: Option<String>,
+    },
+
+    /// List files and directories with their size and modification time
+    FilesDetailed {
+        path: String,
+        #[serde(default)]
+        subpath: Option<String>,
+        #[serde(default)]
+        recursive: bool,
+        /// Add each file's SHA-256
+        #[serde(default)]
+        with_hash: bool,
+    },
+
+    /// List files from a commit's tree instead of the working directory
+    FilesAt {
+        path: String,
+        #[serde(default)]
+        subpath: Option<String>,
+        sha: String,
+        #[serde(flatten)]
+        options: ListOptions,
     },
 
-    /// Read a file (returns base64)
-    Read { path: String, file: String },
+    /// Read a file (returns base64, or text with `encoding: "utf8"`, plus a
+    /// binary flag). Setting `offset` or `length` reads just that range and
+    /// returns it, always base64, with the total size.
+    Read {
+        path: String,
+        file: String,
+        #[serde(default)]
+        offset: Option<u64>,
+        #[serde(default)]
+        length: Option<u64>,
+        #[serde(flatten)]
+        options: ReadOptions,
+    },
+
+    /// Read several files at once; each gets its own ok/err slot, keyed by
+    /// file name
+    ReadMany {
+        path: String,
+        files: Vec<String>,
+        #[serde(flatten)]
+        options: ReadOptions,
+    },
+
+    /// Whether a file or directory exists, and its size and mtime, without
+    /// reading it
+    Stat { path: String, file: String },
+
+    /// Read a file from a commit's tree without checking out (returns base64)
+    ReadAt {
+        path: String,
+        file: String,
+        sha: String,
+        #[serde(flatten)]
+        options: ReadOptions,
+    },
 
     /// Get HEAD commit info
     Head { path: String },
 
-    /// Checkout (hard reset) to a specific commit SHA
-    Checkout { path: String, sha: String },
+    /// Get recent commit history from HEAD
+    Log {
+        path: String,
+        #[serde(default = "default_log_limit")]
+        limit: usize,
+    },
+
+    /// List files changed between two revisions
+    Diff {
+        path: String,
+        from: String,
+        to: String,
+        #[serde(default)]
+        all_files: bool,
+    },
+
+    /// Report uncommitted changes in the working tree
+    Status { path: String },
+
+    /// Checkout (hard reset) to a commit SHA, tag name or ref. Answers with
+    /// the SHA, or the full commit info when `verbose` is set.
+    Checkout {
+        path: String,
+        #[serde(alias = "sha")]
+        rev: String,
+        /// `soft`, `mixed` or `hard` (the default)
+        #[serde(default)]
+        reset_type: Option<String>,
+        #[serde(default)]
+        verbose: bool,
+        /// Fetch a full SHA from `remote` if it isn't in the local history
+        #[serde(default)]
+        fetch_if_missing: bool,
+        /// Remote `fetch_if_missing` fetches from (default `origin`)
+        #[serde(default)]
+        remote: Option<String>,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// Resolve a revision spec (`HEAD~3`, short SHA, branch or tag) to its
+    /// commit without moving anything
+    Resolve { path: String, rev: String },
+
+    /// Check a commit's signature against a set of trusted keys
+    Verify {
+        path: String,
+        sha: String,
+        #[serde(default)]
+        allowed_keys: Vec<String>,
+    },
+
+    /// List local and remote-tracking branches
+    Branches {
+        path: String,
+        /// Remote whose tracking branches lose their prefix (default `origin`)
+        #[serde(default)]
+        remote: Option<String>,
+    },
+
+    /// List tags with their target commit SHAs
+    Tags { path: String },
+
+    /// Fetch a branch without resetting and count commits ahead/behind it
+    AheadBehind {
+        path: String,
+        branch: String,
+        #[serde(default)]
+        remote: Option<String>,
+        #[serde(flatten)]
+        transport: Transport,
+    },
 
     /// Query remote for branch SHA without fetching
-    LsRemote { url: String, branch: String },
+    LsRemote {
+        url: String,
+        branch: String,
+        /// Skip the short-lived result cache (`NOPEA_LS_REMOTE_TTL_SECS`)
+        #[serde(default)]
+        no_cache: bool,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// List a remote's refs, optionally filtered by a wildcard pattern
+    LsRefs {
+        url: String,
+        #[serde(default)]
+        pattern: Option<String>,
+        #[serde(default)]
+        include_tags: bool,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// Name of the branch the remote's HEAD points to
+    DefaultBranch {
+        url: String,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// Fetch the rest of a shallow clone's history
+    Unshallow {
+        path: String,
+        #[serde(default)]
+        remote: Option<String>,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// Repack and prune the repository, answering with the bytes reclaimed.
+    /// Slow on large repositories; don't overlap it with other ops on the
+    /// same path.
+    Gc { path: String },
+
+    /// Disk usage of a repository, split into working tree and git directory
+    RepoSize { path: String },
+
+    /// Whether a cloned repository (one with refs) is at `path`
+    Exists { path: String },
+
+    /// Delete a repository under `NOPEA_REPO_ROOT`; answers with the bytes
+    /// freed
+    Remove { path: String },
+
+    /// Write, stage and commit files on the current branch; answers with the
+    /// new commit's info
+    Commit {
+        path: String,
+        files: Vec<FileWrite>,
+        message: String,
+        author_name: String,
+        author_email: String,
+    },
+
+    /// Create a lightweight or annotated tag; answers with the tag and its
+    /// commit. Fails if the tag exists unless `force` is set.
+    CreateTag {
+        path: String,
+        name: String,
+        #[serde(alias = "sha")]
+        target_sha: String,
+        #[serde(default)]
+        message: String,
+        #[serde(default)]
+        annotated: bool,
+        #[serde(default)]
+        force: bool,
+    },
+
+    /// Push a branch (or a full ref like `refs/tags/v1`) to a remote
+    Push {
+        path: String,
+        #[serde(alias = "ref")]
+        branch: String,
+        #[serde(default)]
+        remote: Option<String>,
+        #[serde(flatten)]
+        transport: Transport,
+    },
+
+    /// Liveness check; answers "pong" without touching disk or network
+    Ping,
+
+    /// Prometheus text snapshot of the sidecar's metrics (needs
+    /// `NOPEA_METRICS`)
+    Metrics,
+
+    /// Supported ops and optional features, without a version check
+    Capabilities,
+
+    /// Which build is running: crate, libgit2 and protocol versions
+    Version,
+
+    /// Run several requests in one frame; each gets its own ok/err slot
+    Batch { requests: Vec<Request> },
+
+    /// Cancel the queued or running request with id `request_id`; answers
+    /// whether there was one. Handled as soon as it's read, ahead of queued
+    /// work
+    Cancel { request_id: u64 },
+
+    /// Reply "bye" and exit cleanly once the reply is written
+    Shutdown,
+}
+
+impl Request {
+    /// Wire name of the op
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Hello { .. } => "hello",
+            Request::Sync { .. } => "sync",
+            Request::FetchCommit { .. } => "fetchcommit",
+            Request::Files { .. } => "files",
+            Request::DetectSource { .. } => "detectsource",
+            Request::FilesDetailed { .. } => "filesdetailed",
+            Request::FilesAt { .. } => "filesat",
+            Request::Read { .. } => "read",
+            Request::ReadMany { .. } => "readmany",
+            Request::Stat { .. } => "stat",
+            Request::ReadAt { .. } => "readat",
+            Request::Head { .. } => "head",
+            Request::Log { .. } => "log",
+            Request::Diff { .. } => "diff",
+            Request::Status { .. } => "status",
+            Request::Checkout { .. } => "checkout",
+            Request::Resolve { .. } => "resolve",
+            Request::Verify { .. } => "verify",
+            Request::Branches { .. } => "branches",
+            Request::Tags { .. } => "tags",
+            Request::AheadBehind { .. } => "aheadbehind",
+            Request::LsRemote { .. } => "lsremote",
+            Request::LsRefs { .. } => "lsrefs",
+            Request::DefaultBranch { .. } => "defaultbranch",
+            Request::Unshallow { .. } => "unshallow",
+            Request::Gc { .. } => "gc",
+            Request::RepoSize { .. } => "reposize",
+            Request::Exists { .. } => "exists",
+            Request::Remove { .. } => "remove",
+            Request::Commit { .. } => "commit",
+            Request::CreateTag { .. } => "createtag",
+            Request::Push { .. } => "push",
+            Request::Ping => "ping",
+            Request::Metrics => "metrics",
+            Request::Capabilities => "capabilities",
+            Request::Version => "version",
+            Request::Batch { .. } => "batch",
+            Request::Cancel { .. } => "cancel",
+            Request::Shutdown => "shutdown",
+        }
+    }
+
+    /// Repository path (or redacted remote URL) the request works on, for logs
+    pub fn target(&self) -> Option<String> {
+        if let Some(path) = self.repo_path() {
+            return Some(path.to_string());
+        }
+        match self {
+            Request::LsRemote { url, .. }
+            | Request::LsRefs { url, .. }
+            | Request::DefaultBranch { url, .. } => Some(logging::redact_url(url)),
+            _ => None,
+        }
+    }
+
+    /// Local repository path the request reads or writes, if any
+    pub fn repo_path(&self) -> Option<&str> {
+        match self {
+            Request::Sync { path, .. }
+            | Request::FetchCommit { path, .. }
+            | Request::Files { path, .. }
+            | Request::DetectSource { path, .. }
+            | Request::FilesDetailed { path, .. }
+            | Request::FilesAt { path, .. }
+            | Request::Read { path, .. }
+            | Request::ReadMany { path, .. }
+            | Request::Stat { path, .. }
+            | Request::ReadAt { path, .. }
+            | Request::Head { path }
+            | Request::Log { path, .. }
+            | Request::Diff { path, .. }
+            | Request::Status { path }
+            | Request::Checkout { path, .. }
+            | Request::Resolve { path, .. }
+            | Request::Verify { path, .. }
+            | Request::Branches { path, .. }
+            | Request::Tags { path }
+            | Request::AheadBehind { path, .. }
+            | Request::Unshallow { path, .. }
+            | Request::Gc { path }
+            | Request::RepoSize { path }
+            | Request::Exists { path }
+            | Request::Remove { path }
+            | Request::Commit { path, .. }
+            | Request::CreateTag { path, .. }
+            | Request::Push { path, .. } => Some(path),
+            Request::LsRemote { .. }
+            | Request::LsRefs { .. }
+            | Request::DefaultBranch { .. }
+            | Request::Hello { .. }
+            | Request::Ping
+            | Request::Metrics
+            | Request::Capabilities
+            | Request::Version
+            | Request::Batch { .. }
+            | Request::Cancel { .. }
+            | Request::Shutdown => None,
+        }
+    }
+}
+
+/// Batch size cap used when `NOPEA_MAX_BATCH_SIZE` is unset
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+
+/// Largest batch the sidecar accepts
+pub fn max_batch_size() -> usize {
+    std::env::var("NOPEA_MAX_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BATCH_SIZE)
 }
 
-fn default_depth() -> u32 {
-    1
+/// Clone depth used when neither the request nor `NOPEA_DEFAULT_DEPTH`
+/// gives one
+pub const DEFAULT_DEPTH: u32 = 1;
+
+/// Depth for syncs that don't specify one: `NOPEA_DEFAULT_DEPTH` (0 for
+/// full history), else `DEFAULT_DEPTH`. Read once; a bad value is logged
+/// and ignored.
+pub fn default_depth() -> u32 {
+    static DEPTH: OnceLock<u32> = OnceLock::new();
+    *DEPTH.get_or_init(|| parse_default_depth(std::env::var("NOPEA_DEFAULT_DEPTH").ok().as_deref()))
+}
+
+fn parse_default_depth(value: Option<&str>) -> u32 {
+    match value.map(str::trim) {
+        None | Some("") => DEFAULT_DEPTH,
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!(
+                value,
+                default = DEFAULT_DEPTH,
+                "ignoring NOPEA_DEFAULT_DEPTH: not a non-negative integer"
+            );
+            DEFAULT_DEPTH
+        }),
+    }
+}
+
+fn default_log_limit() -> usize {
+    git::DEFAULT_LOG_LIMIT
+}
+
+/// Sidecar protocol version and capabilities, returned by `hello`
+#[derive(Debug, Serialize)]
+pub struct HelloInfo {
+    pub version: u32,
+    pub ops: &'static [&'static str],
+}
+
+/// Answer a handshake, rejecting clients newer or older than we support
+pub fn hello(client_version: u32) -> Result<HelloInfo, String> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&client_version) {
+        return Err(format!(
+            "unsupported protocol version {}: sidecar speaks {} to {}",
+            client_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Ok(HelloInfo {
+        version: PROTOCOL_VERSION,
+        ops: SUPPORTED_OPS,
+    })
+}
+
+/// What this sidecar can do, returned by `capabilities`
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub version: u32,
+    pub ops: &'static [&'static str],
+    /// Optional features available in this build and on this host
+    pub features: Vec<&'static str>,
+}
+
+/// Report the supported ops and features. Features that need an external
+/// binary or an env flag are only listed when they would work right now.
+pub fn capabilities() -> Capabilities {
+    let mut features = vec![
+        "compression",
+        "batch",
+        "cancel",
+        "progress",
+        "submodules",
+        "sparse_checkout",
+    ];
+    if cli::available() {
+        features.push("partial_clone");
+    }
+    if cli::lfs_available() {
+        features.push("lfs");
+    }
+    if metrics::enabled() {
+        features.push("metrics");
+    }
+    Capabilities {
+        version: PROTOCOL_VERSION,
+        ops: SUPPORTED_OPS,
+        features,
+    }
+}
+
+/// Build identification, returned by `version`
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    /// Crate version, e.g. `0.1.0`
+    pub version: &'static str,
+    /// Commit the binary was built from, when the build set `NOPEA_GIT_SHA`
+    pub git_sha: Option<&'static str>,
+    /// Version of the linked libgit2, e.g. `1.9.0`
+    pub libgit2: String,
+    pub protocol_version: u32,
+}
+
+/// Report which build is running
+pub fn version() -> VersionInfo {
+    let (major, minor, patch) = git2::Version::get().libgit2_version();
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: option_env!("NOPEA_GIT_SHA").filter(|sha| !sha.is_empty()),
+        libgit2: format!("{}.{}.{}", major, minor, patch),
+        protocol_version: PROTOCOL_VERSION,
+    }
 }
 
 /// Response from Rust to Elixir
@@ -47,14 +672,198 @@ pub enum Response {
     /// Success with string result (commit SHA or base64 content)
     Ok(String),
 
-    /// Success with file list
+    /// Success with a remote's refs
+    OkRefs(Vec<RemoteRef>),
+
+    /// Success with a branch name, or nil when the remote doesn't say
+    OkDefaultBranch(Option<String>),
+
+    /// Success with a sync's old and new SHA and whether it re-cloned (for
+    /// `verbose` or `repair`)
+    OkSync(SyncOutcome),
+
+    /// Success with a dry-run sync's current and target SHA
+    OkSyncPreview(SyncPreview),
+
+    /// Success with ahead/behind counts
+    OkAheadBehind(AheadBehind),
+
+    /// Success with a yes/no answer
+    OkBool(bool),
+
+    /// Success with a byte count
+    OkBytes(u64),
+
+    /// Success with a repository's disk usage
+    OkRepoSize(RepoSize),
+
+    /// Success with a source directory's kind
+    OkSourceKind(SourceKind),
+
+    /// Success with a file's metadata
+    OkStat(FileStat),
+
+    /// Success with a list of names (files or branches)
     OkFiles(Vec<String>),
 
+    /// Success with file and directory metadata
+    OkEntries(Vec<FileEntry>),
+
+    /// Success with file names and content hashes
+    OkHashes(Vec<FileHash>),
+
     /// Success with commit info
     OkCommitInfo(CommitInfo),
 
+    /// Success with HEAD's commit and branch
+    OkHead(HeadInfo),
+
+    /// Success with a list of commits
+    OkCommits(Vec<CommitInfo>),
+
+    /// Success with changed files
+    OkChanges(Vec<FileChange>),
+
+    /// Success with working tree status
+    OkStatus(WorkTreeStatus),
+
+    /// Success with tag list
+    OkTags(Vec<TagInfo>),
+
+    /// Success with a single tag
+    OkTag(TagInfo),
+
+    /// Success with a file's content
+    OkContent(FileContent),
+
+    /// Success with a slice of a file
+    OkChunk(FileChunk),
+
+    /// Success with a commit's signature status
+    OkSignature(SignatureStatus),
+
+    /// Results of a batch, one per sub-request and in the same order
+    OkBatch(Vec<Response>),
+
+    /// Per-file results of a multi-file read, keyed by file name
+    OkFileMap(BTreeMap<String, Response>),
+
+    /// Handshake reply
+    OkHello(HelloInfo),
+
+    /// Supported ops and features
+    OkCapabilities(Capabilities),
+
+    /// Success with the build's versions
+    OkVersion(VersionInfo),
+
+    /// Intermediate fetch progress; the final response follows
+    Progress(TransferProgress),
+
     /// Error
-    Err(String),
+    Err(ErrorInfo),
+}
+
+/// Stable, machine-readable error classes for callers to branch on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    AuthFailed,
+    RepoNotFound,
+    RemoteNotFound,
+    BranchNotFound,
+    RevNotFound,
+    FileNotFound,
+    Network,
+    Timeout,
+    Cancelled,
+    /// Every worker is busy and the queue is full; retry later
+    Busy,
+    Certificate,
+    HostKey,
+    InvalidRequest,
+    TooLarge,
+    BinaryFile,
+    PathEscape,
+    NoCommits,
+    NothingToCommit,
+    AlreadyExists,
+    PushRejected,
+    Unsupported,
+    Io,
+    Git,
+    Internal,
+}
+
+/// Error payload: a stable `code` plus a human-readable `message` for logs
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorInfo {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorInfo {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<GitError> for ErrorInfo {
+    fn from(err: GitError) -> Self {
+        Self::new(error_code(&err), err.to_string())
+    }
+}
+
+/// Classify a `GitError`, looking through libgit2's error code and class
+fn error_code(err: &GitError) -> ErrorCode {
+    match err {
+        GitError::Git(e) => match e.code() {
+            git2::ErrorCode::Auth => ErrorCode::AuthFailed,
+            git2::ErrorCode::Certificate => ErrorCode::Certificate,
+            git2::ErrorCode::Timeout => ErrorCode::Timeout,
+            _ => match e.class() {
+                git2::ErrorClass::Net | git2::ErrorClass::Http | git2::ErrorClass::Ssh => {
+                    ErrorCode::Network
+                }
+                git2::ErrorClass::Ssl => ErrorCode::Certificate,
+                _ => ErrorCode::Git,
+            },
+        },
+        GitError::Io(_) => ErrorCode::Io,
+        GitError::RepoNotFound(_) => ErrorCode::RepoNotFound,
+        GitError::BranchNotFound(_) => ErrorCode::BranchNotFound,
+        GitError::FileNotFound(_) => ErrorCode::FileNotFound,
+        GitError::RevNotFound(_) | GitError::CommitNotPresent(_) | GitError::ShaFetchRefused(_) => {
+            ErrorCode::RevNotFound
+        }
+        GitError::NotACommit(_) => ErrorCode::InvalidRequest,
+        GitError::SshKeyPassphrase(_) => ErrorCode::AuthFailed,
+        GitError::SshKeyNotFound(_) | GitError::CaCertNotFound(_) => ErrorCode::InvalidRequest,
+        GitError::CertificateVerification(_) => ErrorCode::Certificate,
+        GitError::Timeout(_) => ErrorCode::Timeout,
+        GitError::Cancelled => ErrorCode::Cancelled,
+        GitError::FileTooLarge { .. } => ErrorCode::TooLarge,
+        GitError::BinaryFile(_) => ErrorCode::BinaryFile,
+        GitError::Symlink(_) => ErrorCode::InvalidRequest,
+        GitError::PathEscape(_) | GitError::OutsideRoot(_) => ErrorCode::PathEscape,
+        GitError::BareRepository(_) => ErrorCode::InvalidRequest,
+        GitError::HostKey(_) => ErrorCode::HostKey,
+        GitError::RemoteNotFound(_) => ErrorCode::RemoteNotFound,
+        GitError::InvalidSha(_)
+        | GitError::InvalidResetType(_)
+        | GitError::InvalidContent(_)
+        | GitError::InvalidUrl { .. } => ErrorCode::InvalidRequest,
+        GitError::NothingToCommit => ErrorCode::NothingToCommit,
+        GitError::NoCommits(_) => ErrorCode::NoCommits,
+        GitError::TagExists(_) => ErrorCode::AlreadyExists,
+        GitError::PushRejected { .. } => ErrorCode::PushRejected,
+        GitError::RetriesExhausted { source, .. } => error_code(source),
+        GitError::Unsupported(_) => ErrorCode::Unsupported,
+        GitError::Submodule { .. } | GitError::Cli(_) => ErrorCode::Git,
+    }
 }
 
 // Custom serialization to match expected format: {"ok": ...} or {"err": ...}
@@ -64,12 +873,556 @@ impl Serialize for Response {
         S: serde::Serializer,
     {
         let mut map = serializer.serialize_map(Some(1))?;
+        self.serialize_entry(&mut map)?;
+        map.end()
+    }
+}
+
+impl Response {
+    fn serialize_entry<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
         match self {
             Response::Ok(s) => map.serialize_entry("ok", s)?,
+            Response::OkRefs(refs) => map.serialize_entry("ok", refs)?,
+            Response::OkDefaultBranch(branch) => map.serialize_entry("ok", branch)?,
+            Response::OkSync(outcome) => map.serialize_entry("ok", outcome)?,
+            Response::OkSyncPreview(preview) => map.serialize_entry("ok", preview)?,
+            Response::OkAheadBehind(counts) => map.serialize_entry("ok", counts)?,
+            Response::OkBool(answer) => map.serialize_entry("ok", answer)?,
+            Response::OkBytes(bytes) => map.serialize_entry("ok", bytes)?,
+            Response::OkRepoSize(size) => map.serialize_entry("ok", size)?,
+            Response::OkSourceKind(kind) => map.serialize_entry("ok", kind)?,
+            Response::OkStat(stat) => map.serialize_entry("ok", stat)?,
             Response::OkFiles(files) => map.serialize_entry("ok", files)?,
+            Response::OkEntries(entries) => map.serialize_entry("ok", entries)?,
+            Response::OkHashes(hashes) => map.serialize_entry("ok", hashes)?,
             Response::OkCommitInfo(info) => map.serialize_entry("ok", info)?,
+            Response::OkHead(info) => map.serialize_entry("ok", info)?,
+            Response::OkCommits(commits) => map.serialize_entry("ok", commits)?,
+            Response::OkChanges(changes) => map.serialize_entry("ok", changes)?,
+            Response::OkStatus(status) => map.serialize_entry("ok", status)?,
+            Response::OkTags(tags) => map.serialize_entry("ok", tags)?,
+            Response::OkTag(tag) => map.serialize_entry("ok", tag)?,
+            Response::OkContent(content) => map.serialize_entry("ok", content)?,
+            Response::OkChunk(chunk) => map.serialize_entry("ok", chunk)?,
+            Response::OkSignature(status) => map.serialize_entry("ok", status)?,
+            Response::OkBatch(responses) => map.serialize_entry("ok", responses)?,
+            Response::OkFileMap(results) => map.serialize_entry("ok", results)?,
+            Response::OkHello(info) => map.serialize_entry("ok", info)?,
+            Response::OkCapabilities(caps) => map.serialize_entry("ok", caps)?,
+            Response::OkVersion(info) => map.serialize_entry("ok", info)?,
+            Response::Progress(progress) => map.serialize_entry("progress", progress)?,
             Response::Err(e) => map.serialize_entry("err", e)?,
         }
+        Ok(())
+    }
+}
+
+/// A response frame, tagged with the id of the request it answers
+#[derive(Debug)]
+pub struct Reply<'a> {
+    pub id: Option<u64>,
+    /// Time spent handling the request, when the client asked for `timing`
+    pub duration_ms: Option<u64>,
+    /// Frame the payload with a header byte (not part of the payload)
+    pub compress: bool,
+    pub response: &'a Response,
+}
+
+impl<'a> Reply<'a> {
+    pub fn new(id: Option<u64>, response: &'a Response) -> Self {
+        Self {
+            id,
+            duration_ms: None,
+            compress: false,
+            response,
+        }
+    }
+}
+
+impl Serialize for Reply<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let len = 1 + self.id.is_some() as usize + self.duration_ms.is_some() as usize;
+        let mut map = serializer.serialize_map(Some(len))?;
+        if let Some(id) = self.id {
+            map.serialize_entry("id", &id)?;
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            map.serialize_entry("duration_ms", &duration_ms)?;
+        }
+        self.response.serialize_entry(&mut map)?;
         map.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct SyncFrame<'a> {
+        op: &'a str,
+        url: &'a str,
+        branch: &'a str,
+        path: &'a str,
+        depth: u32,
+        username: &'a str,
+        ssh_private_key_path: &'a str,
+        proxy_url: &'a str,
+    }
+
+    #[test]
+    fn test_default_depth_from_env_value() {
+        assert_eq!(parse_default_depth(None), DEFAULT_DEPTH);
+        assert_eq!(parse_default_depth(Some(" 50 ")), 50);
+        assert_eq!(parse_default_depth(Some("0")), 0);
+        assert_eq!(parse_default_depth(Some("-3")), DEFAULT_DEPTH);
+        assert_eq!(parse_default_depth(Some("deep")), DEFAULT_DEPTH);
+    }
+
+    #[test]
+    fn test_sync_decodes_flattened_credentials() {
+        let frame = SyncFrame {
+            op: "sync",
+            url: "https://example.com/repo.git",
+            branch: "main",
+            path: "/tmp/repo",
+            depth: 3,
+            username: "deploy",
+            ssh_private_key_path: "/keys/id_ed25519",
+            proxy_url: "http://proxy:3128",
+        };
+        let payload = rmp_serde::to_vec_named(&frame).unwrap();
+
+        let envelope: Envelope = rmp_serde::from_slice(&payload).unwrap();
+
+        assert_eq!(envelope.id, None);
+        match envelope.request {
+            Request::Sync {
+                depth, transport, ..
+            } => {
+                let credentials = transport.creds();
+                assert_eq!(depth, 3);
+                assert_eq!(credentials.username.as_deref(), Some("deploy"));
+                assert_eq!(credentials.password, None);
+                assert_eq!(
+                    credentials.ssh_private_key_path.as_deref(),
+                    Some("/keys/id_ed25519")
+                );
+                assert_eq!(transport.proxy_url.as_deref(), Some("http://proxy:3128"));
+                assert!(!transport.insecure_skip_tls_verify);
+            }
+            other => panic!("expected sync request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ls_remote_decodes_insecure_skip_tls_verify() {
+        #[derive(Serialize)]
+        struct Frame<'a> {
+            op: &'a str,
+            url: &'a str,
+            branch: &'a str,
+            insecure_skip_tls_verify: bool,
+        }
+        let payload = rmp_serde::to_vec_named(&Frame {
+            op: "lsremote",
+            url: "https://gitlab.test/app.git",
+            branch: "main",
+            insecure_skip_tls_verify: true,
+        })
+        .unwrap();
+
+        match rmp_serde::from_slice::<Envelope>(&payload).unwrap().request {
+            Request::LsRemote { transport, .. } => assert!(transport.insecure_skip_tls_verify),
+            other => panic!("expected lsremote request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_push_decodes_inline_credentials_object() {
+        #[derive(Serialize)]
+        struct Inline<'a> {
+            token: &'a str,
+        }
+        #[derive(Serialize)]
+        struct PushFrame<'a> {
+            op: &'a str,
+            path: &'a str,
+            branch: &'a str,
+            username: &'a str,
+            credentials: Inline<'a>,
+        }
+        let payload = rmp_serde::to_vec_named(&PushFrame {
+            op: "push",
+            path: "/tmp/repo",
+            branch: "main",
+            username: "ignored",
+            credentials: Inline {
+                token: "ghp_secret",
+            },
+        })
+        .unwrap();
+
+        let request: Request = rmp_serde::from_slice(&payload).unwrap();
+
+        match request {
+            Request::Push { transport, .. } => {
+                // The nested object replaces the top-level fields entirely
+                assert_eq!(
+                    transport.creds().userpass(),
+                    Some(("x-access-token", "ghp_secret"))
+                );
+                assert!(!format!("{:?}", transport).contains("ghp_secret"));
+            }
+            other => panic!("expected push request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_envelope_carries_request_id() {
+        #[derive(Serialize)]
+        struct HeadFrame<'a> {
+            id: u64,
+            op: &'a str,
+            path: &'a str,
+        }
+        let payload = rmp_serde::to_vec_named(&HeadFrame {
+            id: 42,
+            op: "head",
+            path: "/tmp/repo",
+        })
+        .unwrap();
+
+        let envelope: Envelope = rmp_serde::from_slice(&payload).unwrap();
+
+        assert_eq!(envelope.id, Some(42));
+        assert!(matches!(envelope.request, Request::Head { ref path } if path == "/tmp/repo"));
+    }
+
+    #[test]
+    fn test_reply_echoes_id_alongside_result() {
+        let response = Response::Progress(TransferProgress {
+            received_objects: 1,
+            total_objects: 2,
+            indexed_objects: 0,
+            received_bytes: 10,
+        });
+        let payload = rmp_serde::to_vec_named(&Reply::new(Some(7), &response)).unwrap();
+
+        #[derive(Deserialize)]
+        struct Frame {
+            id: u64,
+            progress: Progress,
+        }
+        #[derive(Deserialize)]
+        struct Progress {
+            received_objects: usize,
+            total_objects: usize,
+        }
+        let frame: Frame = rmp_serde::from_slice(&payload).unwrap();
+
+        assert_eq!(frame.id, 7);
+        assert_eq!(frame.progress.received_objects, 1);
+        assert_eq!(frame.progress.total_objects, 2);
+    }
+
+    #[test]
+    fn test_batch_decodes_sub_requests_in_order() {
+        #[derive(Serialize)]
+        struct Sub<'a> {
+            op: &'a str,
+            path: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            file: Option<&'a str>,
+        }
+        #[derive(Serialize)]
+        struct BatchFrame<'a> {
+            op: &'a str,
+            requests: Vec<Sub<'a>>,
+        }
+        let payload = rmp_serde::to_vec_named(&BatchFrame {
+            op: "batch",
+            requests: vec![
+                Sub {
+                    op: "files",
+                    path: "/tmp/repo",
+                    file: None,
+                },
+                Sub {
+                    op: "read",
+                    path: "/tmp/repo",
+                    file: Some("deploy.yaml"),
+                },
+            ],
+        })
+        .unwrap();
+
+        let envelope: Envelope = rmp_serde::from_slice(&payload).unwrap();
+
+        match envelope.request {
+            Request::Batch { requests } => {
+                assert_eq!(requests.len(), 2);
+                assert!(matches!(requests[0], Request::Files { .. }));
+                assert!(
+                    matches!(requests[1], Request::Read { ref file, .. } if file == "deploy.yaml")
+                );
+            }
+            other => panic!("expected batch request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_batch_response_nests_each_result() {
+        let response = Response::OkBatch(vec![
+            Response::Ok("abc".to_string()),
+            Response::Err(GitError::FileNotFound("x".to_string()).into()),
+        ]);
+        let payload = rmp_serde::to_vec_named(&response).unwrap();
+
+        #[derive(Deserialize)]
+        struct Frame {
+            ok: (Slot, Failure),
+        }
+        #[derive(Deserialize)]
+        struct Slot {
+            ok: String,
+        }
+        #[derive(Deserialize)]
+        struct Failure {
+            err: Detail,
+        }
+        #[derive(Deserialize)]
+        struct Detail {
+            code: String,
+            message: String,
+        }
+        let frame: Frame = rmp_serde::from_slice(&payload).unwrap();
+
+        assert_eq!(frame.ok.0.ok, "abc");
+        assert_eq!(frame.ok.1.err.code, "file_not_found");
+        assert_eq!(frame.ok.1.err.message, "file not found: x");
+    }
+
+    #[test]
+    fn test_ping_needs_no_fields() {
+        #[derive(Serialize)]
+        struct PingFrame<'a> {
+            op: &'a str,
+        }
+        let payload = rmp_serde::to_vec_named(&PingFrame { op: "ping" }).unwrap();
+
+        let envelope: Envelope = rmp_serde::from_slice(&payload).unwrap();
+
+        assert!(matches!(envelope.request, Request::Ping));
+    }
+
+    #[test]
+    fn test_checkout_is_terse_unless_verbose() {
+        #[derive(Serialize)]
+        struct CheckoutFrame<'a> {
+            op: &'a str,
+            path: &'a str,
+            sha: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            verbose: Option<bool>,
+        }
+        let decode = |verbose| {
+            let frame = CheckoutFrame {
+                op: "checkout",
+                path: "/tmp/repo",
+                sha: "abc123",
+                verbose,
+            };
+            let payload = rmp_serde::to_vec_named(&frame).unwrap();
+            rmp_serde::from_slice::<Request>(&payload).unwrap()
+        };
+
+        assert!(matches!(
+            decode(None),
+            Request::Checkout { verbose: false, .. }
+        ));
+        assert!(matches!(
+            decode(Some(true)),
+            Request::Checkout { verbose: true, .. }
+        ));
+    }
+
+    #[test]
+    fn test_hello_rejects_incompatible_versions() {
+        let info = hello(PROTOCOL_VERSION).unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION);
+        assert!(info.ops.contains(&"sync"));
+
+        assert!(hello(PROTOCOL_VERSION + 1).is_err());
+        assert!(hello(MIN_PROTOCOL_VERSION - 1).is_err());
+    }
+
+    #[test]
+    fn test_supported_ops_are_all_known() {
+        #[derive(Serialize)]
+        struct OpFrame<'a> {
+            op: &'a str,
+        }
+
+        for op in SUPPORTED_OPS {
+            let payload = rmp_serde::to_vec_named(&OpFrame { op }).unwrap();
+            // Missing fields are fine; an unknown op is not
+            if let Err(e) = rmp_serde::from_slice::<Request>(&payload) {
+                assert!(
+                    !e.to_string().contains("unknown variant"),
+                    "op '{}' is advertised but not handled: {}",
+                    op,
+                    e
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_git_errors_map_to_stable_codes() {
+        let code = |err: GitError| ErrorInfo::from(err).code;
+
+        assert_eq!(
+            code(GitError::BranchNotFound("main".to_string())),
+            ErrorCode::BranchNotFound
+        );
+        assert_eq!(code(GitError::Timeout(30)), ErrorCode::Timeout);
+        assert_eq!(
+            code(GitError::Git(git2::Error::new(
+                git2::ErrorCode::Auth,
+                git2::ErrorClass::Http,
+                "authentication required",
+            ))),
+            ErrorCode::AuthFailed
+        );
+        assert_eq!(
+            code(GitError::RetriesExhausted {
+                attempts: 4,
+                source: Box::new(GitError::Git(git2::Error::new(
+                    git2::ErrorCode::GenericError,
+                    git2::ErrorClass::Net,
+                    "failed to resolve address",
+                ))),
+            }),
+            ErrorCode::Network
+        );
+    }
+
+    #[test]
+    fn test_err_serializes_code_and_message() {
+        let response = Response::Err(ErrorInfo::new(ErrorCode::InvalidRequest, "bad"));
+        let payload = rmp_serde::to_vec_named(&response).unwrap();
+
+        #[derive(Deserialize)]
+        struct Frame {
+            err: std::collections::BTreeMap<String, String>,
+        }
+        let frame: Frame = rmp_serde::from_slice(&payload).unwrap();
+
+        assert_eq!(frame.err["code"], "invalid_request");
+        assert_eq!(frame.err["message"], "bad");
+    }
+
+    #[test]
+    fn test_every_handled_op_is_supported() {
+        #[derive(Serialize)]
+        struct OpFrame<'a> {
+            op: &'a str,
+        }
+        // serde lists every variant it knows in the unknown-variant error
+        let payload = rmp_serde::to_vec_named(&OpFrame { op: "no-such-op" }).unwrap();
+        let err = rmp_serde::from_slice::<Request>(&payload)
+            .unwrap_err()
+            .to_string();
+        let (_, expected) = err.split_once("expected one of ").unwrap();
+        let mut handled: Vec<&str> = expected
+            .split(", ")
+            .map(|op| op.trim_matches('`'))
+            .collect();
+        let mut supported = SUPPORTED_OPS.to_vec();
+        handled.sort_unstable();
+        supported.sort_unstable();
+
+        assert_eq!(handled, supported);
+    }
+
+    #[test]
+    fn test_capabilities_lists_ops_and_features() {
+        let caps = capabilities();
+
+        assert_eq!(caps.version, PROTOCOL_VERSION);
+        assert!(caps.ops.contains(&"capabilities"));
+        assert!(caps.features.contains(&"compression"));
+        assert_eq!(caps.features.contains(&"lfs"), cli::lfs_available());
+    }
+
+    #[test]
+    fn test_version_reports_crate_and_libgit2() {
+        let info = version();
+
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(capabilities().ops.contains(&"version"));
+        let libgit2: Vec<u32> = info
+            .libgit2
+            .split('.')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        assert_eq!(libgit2.len(), 3);
+        assert!(libgit2[0] >= 1);
+
+        // Nil rather than missing when the build didn't record a commit
+        let value = serde_json::to_value(Response::OkVersion(info)).unwrap();
+        assert!(value["ok"].get("git_sha").is_some());
+    }
+
+    #[test]
+    fn test_op_names_are_advertised() {
+        let requests = [
+            Request::Ping,
+            Request::Shutdown,
+            Request::Head {
+                path: "/tmp/repo".to_string(),
+            },
+            Request::Batch { requests: vec![] },
+        ];
+
+        for request in &requests {
+            assert!(SUPPORTED_OPS.contains(&request.op()));
+        }
+    }
+
+    #[test]
+    fn test_reply_includes_duration_when_timed() {
+        #[derive(Serialize)]
+        struct TimedFrame<'a> {
+            op: &'a str,
+            timing: bool,
+        }
+        let payload = rmp_serde::to_vec_named(&TimedFrame {
+            op: "ping",
+            timing: true,
+        })
+        .unwrap();
+        let envelope: Envelope = rmp_serde::from_slice(&payload).unwrap();
+        assert!(envelope.timing);
+
+        let response = Response::Ok("pong".to_string());
+        let payload = rmp_serde::to_vec_named(&Reply {
+            duration_ms: Some(12),
+            ..Reply::new(None, &response)
+        })
+        .unwrap();
+
+        #[derive(Deserialize)]
+        struct Frame {
+            ok: String,
+            duration_ms: u64,
+        }
+        let frame: Frame = rmp_serde::from_slice(&payload).unwrap();
+        assert_eq!(frame.ok, "pong");
+        assert_eq!(frame.duration_ms, 12);
+    }
+}